@@ -0,0 +1,31 @@
+#!/ic-repl
+// Make 10k query calls to a greet canister on a local replica:
+//   time ic-repl -r local bench.sh
+// Not run against a replica yet: compare the timings with and without the shared runtime
+// before relying on this benchmark.
+function deploy(wasm) {
+  let id = call ic.provisional_create_canister_with_cycles(record { settings = null; amount = null });
+  call ic.canister_status(id);
+  assert _.module_hash == (null : opt blob);
+  call ic.install_code(
+    record {
+      arg = encode wasm.__init_args();
+      wasm_module = wasm;
+      mode = variant { install };
+      canister_id = id.canister_id;
+    },
+  );
+  id
+};
+
+let id = deploy(file("greet.wasm"));
+let canister = id.canister_id;
+let xs = vec { 0; 1; 2; 3; 4; 5; 6; 7; 8; 9 };
+function greet(x) { let _ = call canister.greet("test") };
+function ten(x) { let _ = xs.map(greet) };
+function hundred(x) { let _ = xs.map(ten) };
+function thousand(x) { let _ = xs.map(hundred) };
+let res = xs.map(thousand);
+assert res[9][9][9][9] == "Hello, test!";
+call ic.stop_canister(id);
+call ic.delete_canister(id);
@@ -0,0 +1,171 @@
+use crate::utils::get_reject;
+use anyhow::Result;
+use candid::{types::Type, IDLArgs, IDLValue, Principal, TypeEnv};
+use ic_agent::{Agent, RequestId};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Appends one JSON line per canister interaction to a file.
+#[derive(Clone)]
+pub struct CallLog(Arc<Mutex<File>>);
+
+#[derive(Serialize)]
+pub struct CallRecord {
+    /// Nanoseconds since the UNIX epoch when the interaction started
+    pub timestamp: u64,
+    pub sender: Option<String>,
+    pub canister_id: String,
+    pub method: String,
+    /// One of query, update, read_state
+    pub mode: &'static str,
+    pub effective_canister_id: Option<String>,
+    pub request_id: Option<String>,
+    pub args: Option<String>,
+    pub args_hex: Option<String>,
+    pub reply: Option<String>,
+    pub reply_hex: Option<String>,
+    pub duration_ms: f64,
+    /// One of ok, signed, submitted, reject, error
+    pub outcome: &'static str,
+    pub reject_code: Option<u8>,
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+}
+
+pub struct LogEntry {
+    start: Instant,
+    record: CallRecord,
+}
+
+impl CallLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(CallLog(Arc::new(Mutex::new(file))))
+    }
+    pub fn entry(
+        &self,
+        agent: &Agent,
+        canister_id: Principal,
+        method: &str,
+        mode: &'static str,
+        effective_id: Option<Principal>,
+    ) -> LogEntry {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        LogEntry {
+            start: Instant::now(),
+            record: CallRecord {
+                timestamp,
+                sender: agent.get_principal().ok().map(|p| p.to_text()),
+                canister_id: canister_id.to_text(),
+                method: method.to_string(),
+                mode,
+                effective_canister_id: effective_id.map(|id| id.to_text()),
+                request_id: None,
+                args: None,
+                args_hex: None,
+                reply: None,
+                reply_hex: None,
+                duration_ms: 0.0,
+                outcome: "ok",
+                reject_code: None,
+                error_code: None,
+                error: None,
+            },
+        }
+    }
+    /// Log the raw reply of a canister call. `rets` are used to render the reply as Candid text.
+    pub fn finish(
+        &self,
+        mut entry: LogEntry,
+        result: Result<&[u8], &anyhow::Error>,
+        rets: Option<(&TypeEnv, &[Type])>,
+    ) {
+        match result {
+            Ok(bytes) => {
+                entry.record.reply = Some(bytes_to_text(bytes, rets));
+                entry.record.reply_hex = Some(hex::encode(bytes));
+            }
+            Err(e) => entry.set_error(e),
+        }
+        self.write(entry);
+    }
+    /// Log an interaction whose result is already a value, e.g., read_state.
+    pub fn finish_value(&self, mut entry: LogEntry, result: Result<&IDLValue, &anyhow::Error>) {
+        match result {
+            Ok(v) => entry.record.reply = Some(v.to_string()),
+            Err(e) => entry.set_error(e),
+        }
+        self.write(entry);
+    }
+    /// Log a message that is only signed but not sent, used in offline mode.
+    pub fn finish_signed(&self, mut entry: LogEntry) {
+        entry.record.outcome = "signed";
+        self.write(entry);
+    }
+    /// Log an update call that is sent without waiting for the reply, used by `submit`.
+    pub fn finish_submitted(&self, mut entry: LogEntry) {
+        entry.record.outcome = "submitted";
+        self.write(entry);
+    }
+    fn write(&self, mut entry: LogEntry) {
+        entry.record.duration_ms = entry.start.elapsed().as_secs_f64() * 1000.0;
+        let res = serde_json::to_string(&entry.record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| {
+                let mut file = self.0.lock().unwrap();
+                writeln!(file, "{line}")?;
+                Ok(())
+            });
+        if let Err(e) = res {
+            eprintln!("Warning: cannot write to call log: {e}");
+        }
+    }
+}
+
+impl LogEntry {
+    pub fn args(mut self, bytes: &[u8], types: Option<(&TypeEnv, &[Type])>) -> Self {
+        self.record.args = Some(bytes_to_text(bytes, types));
+        self.record.args_hex = Some(hex::encode(bytes));
+        self
+    }
+    /// Override the sender for messages signed elsewhere.
+    pub fn sender(mut self, sender: Principal) -> Self {
+        self.record.sender = Some(sender.to_text());
+        self
+    }
+    pub fn set_request_id(&mut self, request_id: &RequestId) {
+        self.record.request_id = Some(hex::encode(request_id.as_slice()));
+    }
+    fn set_error(&mut self, e: &anyhow::Error) {
+        match get_reject(e) {
+            Some(reject) => {
+                self.record.outcome = "reject";
+                self.record.reject_code = Some(reject.reject_code as u8);
+                self.record.error_code.clone_from(&reject.error_code);
+                self.record.error = Some(reject.reject_message.clone());
+            }
+            None => {
+                self.record.outcome = "error";
+                self.record.error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+fn bytes_to_text(bytes: &[u8], types: Option<(&TypeEnv, &[Type])>) -> String {
+    let args = match types {
+        Some((env, types)) => IDLArgs::from_bytes_with_types(bytes, env, types),
+        None => IDLArgs::from_bytes(bytes),
+    };
+    match args {
+        Ok(args) => args.to_string(),
+        Err(_) => IDLValue::Blob(bytes.to_vec()).to_string(),
+    }
+}
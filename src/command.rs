@@ -1,11 +1,15 @@
 use super::error::pretty_parse;
 use super::exp::Exp;
-use super::helper::{did_to_canister_info, FileSource, MyHelper};
+use super::helper::{
+    did_to_canister_info, known_network_url, replica_network, FileSource, MyHelper,
+};
 use super::token::{ParserError, Tokenizer};
 use super::utils::{get_dfx_hsm_pin, resolve_path};
 use anyhow::{anyhow, Context};
 use candid::{types::value::IDLValue, Principal, TypeEnv};
 use candid_parser::configs::Configs;
+use futures::future::LocalBoxFuture;
+use ic_agent::Identity;
 use pretty_assertions::{assert_eq, assert_ne};
 use std::ops::Range;
 use std::sync::Arc;
@@ -19,9 +23,13 @@ pub enum Command {
     Show(Exp),
     Let(String, Exp),
     Assert(BinOp, Exp, Exp),
-    Import(String, Principal, Option<String>),
+    Import(String, Principal, Option<String>, Option<String>),
+    ImportProject(String, Option<String>),
     Load(Exp),
     Identity(String, IdentityConfig),
+    Network(String, Option<Exp>),
+    Replica(String),
+    Seed(String),
     Func {
         name: String,
         args: Vec<String>,
@@ -41,7 +49,16 @@ pub enum Command {
 pub enum IdentityConfig {
     Empty,
     Pem(String),
-    Hsm { slot_index: usize, key_id: String },
+    Hsm {
+        slot_index: usize,
+        key_id: String,
+        lib_path: Option<String>,
+    },
+    Mnemonic(String),
+    Delegation {
+        chain: String,
+        key: String,
+    },
 }
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
@@ -53,27 +70,59 @@ pub enum BinOp {
 
 impl Command {
     pub fn run(self, helper: &mut MyHelper) -> anyhow::Result<()> {
+        let runtime = helper.runtime.clone();
+        crate::helper::block_on(&runtime, self.run_async(helper))
+    }
+    /// Run the command, awaiting canister calls as in `Exp::eval_async`.
+    pub fn run_async(self, helper: &mut MyHelper) -> LocalBoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(self.run_inner(helper))
+    }
+    async fn run_inner(self, helper: &mut MyHelper) -> anyhow::Result<()> {
         match self {
-            Command::Import(id, canister_id, did) => {
+            Command::Import(id, canister_id, did, network) => {
                 if let Some(did) = &did {
                     let path = resolve_path(&helper.base_path, did);
                     let info = did_to_canister_info(did, FileSource::Path(&path), None)?;
-                    helper.canister_map.borrow_mut().0.insert(canister_id, info);
+                    let network = network.unwrap_or_else(|| helper.current_network.clone());
+                    helper.add_canisters(&network, vec![(canister_id, info)]);
                 }
                 // TODO decide if it's a Service instead
                 helper.env.0.insert(id, IDLValue::Principal(canister_id));
             }
+            Command::ImportProject(path, network) => {
+                let path = resolve_path(&helper.base_path, &path);
+                let network = network.unwrap_or_else(|| helper.current_network.clone());
+                let project = crate::dfx::load_project(&path, &network)?;
+                helper.network_urls.borrow_mut().extend(project.networks);
+                let mut infos = Vec::new();
+                for canister in &project.canisters {
+                    if let Some(did) = &canister.did {
+                        let info =
+                            did_to_canister_info(&canister.name, FileSource::Path(did), None)?;
+                        infos.push((canister.id, info));
+                    }
+                }
+                helper.add_canisters(&network, infos);
+                for canister in &project.canisters {
+                    let name = canister.name.replace('-', "_");
+                    helper.env.0.insert(name, IDLValue::Principal(canister.id));
+                }
+                println!(
+                    "Imported {} canisters for network {network}",
+                    project.canisters.len()
+                );
+            }
             Command::Let(id, val) => {
                 let is_call = val.is_call();
-                let v = val.eval(helper)?;
+                let v = val.eval_async(helper).await?;
                 bind_value(helper, id, v, is_call, false);
             }
             Command::Func { name, args, body } => {
                 helper.func_env.0.insert(name, (args, body));
             }
             Command::Assert(op, left, right) => {
-                let left = left.eval(helper)?;
-                let right = right.eval(helper)?;
+                let left = left.eval_async(helper).await?;
+                let right = right.eval_async(helper).await?;
                 match op {
                     BinOp::Equal => assert_eq!(left, right),
                     BinOp::SubEqual => {
@@ -107,7 +156,7 @@ impl Command {
             Command::Show(val) => {
                 let is_call = val.is_call();
                 let time = Instant::now();
-                let v = val.eval(helper)?;
+                let v = val.eval_async(helper).await?;
                 let duration = time.elapsed();
                 bind_value(helper, "_".to_string(), v, is_call, true);
                 if helper.verbose {
@@ -116,39 +165,75 @@ impl Command {
                 }
             }
             Command::Identity(id, config) => {
-                use ic_agent::identity::{BasicIdentity, Identity, Secp256k1Identity};
+                use ic_agent::identity::BasicIdentity;
                 let identity: Arc<dyn Identity> = match &config {
-                    IdentityConfig::Hsm { slot_index, key_id } => {
-                        #[cfg(target_os = "macos")]
-                        const PKCS11_LIBPATH: &str = "/Library/OpenSC/lib/pkcs11/opensc-pkcs11.so";
-                        #[cfg(target_os = "linux")]
-                        const PKCS11_LIBPATH: &str = "/usr/lib/x86_64-linux-gnu/opensc-pkcs11.so";
-                        #[cfg(target_os = "windows")]
-                        const PKCS11_LIBPATH: &str =
-                            "C:/Program Files/OpenSC Project/OpenSC/pkcs11/opensc-pkcs11.dll";
-                        let lib_path = std::env::var("PKCS11_LIBPATH")
-                            .unwrap_or_else(|_| PKCS11_LIBPATH.to_string());
-                        Arc::from(ic_identity_hsm::HardwareIdentity::new(
-                            lib_path,
+                    IdentityConfig::Hsm {
+                        slot_index,
+                        key_id,
+                        lib_path,
+                    } => {
+                        let lib_path = match lib_path {
+                            Some(path) => resolve_path(&helper.base_path, path)
+                                .to_string_lossy()
+                                .to_string(),
+                            None => crate::hsm::default_lib_path(),
+                        };
+                        // Only prompt for the PIN once per token in a session
+                        let cache_key = (lib_path.clone(), *slot_index);
+                        let cached = helper.hsm_pins.get(&cache_key).cloned();
+                        let entered = std::cell::RefCell::new(None);
+                        let identity = ic_identity_hsm::HardwareIdentity::new(
+                            &lib_path,
                             *slot_index,
                             key_id,
-                            get_dfx_hsm_pin,
-                        )?)
+                            || {
+                                let pin = match cached {
+                                    Some(pin) => pin,
+                                    None => get_dfx_hsm_pin()?,
+                                };
+                                *entered.borrow_mut() = Some(pin.clone());
+                                Ok(pin)
+                            },
+                        );
+                        let identity = match identity {
+                            Ok(identity) => identity,
+                            Err(e) => {
+                                // the cached PIN may be wrong, ask again next time
+                                helper.hsm_pins.remove(&cache_key);
+                                return Err(e.into());
+                            }
+                        };
+                        if let Some(pin) = entered.into_inner() {
+                            helper.hsm_pins.insert(cache_key, pin);
+                        }
+                        Arc::from(identity)
                     }
                     IdentityConfig::Pem(pem_path) => {
-                        let pem_path = resolve_path(&helper.base_path, pem_path);
-                        match Secp256k1Identity::from_pem_file(&pem_path) {
-                            Ok(identity) => Arc::from(identity),
-                            Err(_) => Arc::from(BasicIdentity::from_pem_file(&pem_path)?),
-                        }
+                        crate::identity::from_pem_file(&resolve_path(&helper.base_path, pem_path))?
                     }
+                    IdentityConfig::Mnemonic(phrase) => crate::identity::from_mnemonic(phrase)?,
+                    IdentityConfig::Delegation { chain, key } => crate::identity::from_delegation(
+                        &resolve_path(&helper.base_path, chain),
+                        &resolve_path(&helper.base_path, key),
+                    )?,
                     IdentityConfig::Empty => match helper.identity_map.0.get(&id) {
                         Some(identity) => identity.clone(),
-                        None => Arc::from(BasicIdentity::from_signing_key(
-                            ed25519_consensus::SigningKey::new(rand::thread_rng()),
-                        )),
+                        None => match (&helper.seed, crate::dfx::identity_pem(&id)) {
+                            (Some(seed), _) => crate::identity::from_seed(seed, &id),
+                            (None, Some(pem_path)) => {
+                                println!("Load dfx identity from {}", pem_path.display());
+                                crate::identity::from_pem_file(&pem_path)?
+                            }
+                            (None, None) => Arc::from(BasicIdentity::from_signing_key(
+                                ed25519_consensus::SigningKey::new(rand::thread_rng()),
+                            )),
+                        },
                     },
                 };
+                let chain = identity.delegation_chain();
+                if !chain.is_empty() {
+                    println!("{}", crate::identity::check_delegation(&chain)?);
+                }
                 helper
                     .identity_map
                     .0
@@ -160,10 +245,35 @@ impl Command {
                 helper.current_identity = id.to_string();
                 helper.env.0.insert(id, IDLValue::Principal(sender));
             }
+            Command::Network(name, url) => {
+                let network = match url {
+                    Some(url) => {
+                        let IDLValue::Text(url) = url.eval_async(helper).await? else {
+                            return Err(anyhow!("network expects a replica url"));
+                        };
+                        let url = known_network_url(&url).unwrap_or(&url);
+                        Some(helper.connect(url)?)
+                    }
+                    None => None,
+                };
+                helper.switch_network(&name, network)?;
+                println!("Current network {name} ({})", helper.agent_url);
+            }
+            Command::Replica(replica) => {
+                let (name, url) = replica_network(&replica);
+                println!("Ping {url}...");
+                let network = helper.connect(url)?;
+                helper.switch_network(name, Some(network))?;
+                helper.load_prelude()?;
+                println!("Current network {name} ({url})");
+            }
+            Command::Seed(seed) => {
+                helper.seed = Some(seed);
+            }
             Command::Load(e) => {
                 // TODO check for infinite loop
                 // Note that it's a bit tricky to make load as a built-in function, as it requires mutable access to helper.
-                let IDLValue::Text(file) = e.eval(helper)? else {
+                let IDLValue::Text(file) = e.eval_async(helper).await? else {
                     return Err(anyhow!("load needs to be a file path"));
                 };
                 let (file, fail_safe) = if file.ends_with('?') {
@@ -190,33 +300,33 @@ impl Command {
                     if helper.verbose {
                         println!("> {}", &script[pos]);
                     }
-                    cmd.run(helper)?;
+                    cmd.run_async(helper).await?;
                 }
                 helper.base_path = old_base;
             }
             Command::If { cond, then, else_ } => {
-                let IDLValue::Bool(cond) = cond.eval(helper)? else {
+                let IDLValue::Bool(cond) = cond.eval_async(helper).await? else {
                     return Err(anyhow!("if condition is not a boolean expression"));
                 };
                 if cond {
                     for cmd in then.into_iter() {
-                        cmd.run(helper)?;
+                        cmd.run_async(helper).await?;
                     }
                 } else {
                     for cmd in else_.into_iter() {
-                        cmd.run(helper)?;
+                        cmd.run_async(helper).await?;
                     }
                 }
             }
             Command::While { cond, body } => loop {
-                let IDLValue::Bool(cond) = cond.clone().eval(helper)? else {
+                let IDLValue::Bool(cond) = cond.clone().eval_async(helper).await? else {
                     return Err(anyhow!("while condition is not a boolean expression"));
                 };
                 if !cond {
                     break;
                 }
                 for cmd in body.iter() {
-                    cmd.clone().run(helper)?;
+                    cmd.clone().run_async(helper).await?;
                 }
             },
         }
@@ -0,0 +1,168 @@
+//! Read dfx projects and the dfx config directory.
+use anyhow::{anyhow, Context, Result};
+use candid::Principal;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub struct ProjectCanister {
+    pub name: String,
+    pub id: Principal,
+    pub did: Option<PathBuf>,
+}
+pub struct Project {
+    pub canisters: Vec<ProjectCanister>,
+    /// Replica URLs from `networks.json` and the `networks` field of `dfx.json`
+    pub networks: BTreeMap<String, String>,
+}
+
+/// `$DFX_CONFIG_ROOT/.config/dfx`, or `~/.config/dfx`.
+pub fn config_dir() -> PathBuf {
+    match std::env::var("DFX_CONFIG_ROOT") {
+        Ok(root) => Path::new(&root).join(".config/dfx"),
+        Err(_) => PathBuf::from(shellexpand::tilde("~/.config/dfx").as_ref()),
+    }
+}
+
+/// The pem file of a dfx identity, either `identity.pem` or `identity.pem.encrypted`, if it exists.
+pub fn identity_pem(name: &str) -> Option<PathBuf> {
+    let dir = config_dir().join("identity").join(name);
+    ["identity.pem", "identity.pem.encrypted"]
+        .into_iter()
+        .map(|file| dir.join(file))
+        .find(|path| path.exists())
+}
+
+/// Read the canister ids and interfaces of a dfx project for `network`.
+/// `path` is either the project directory or its `dfx.json`.
+pub fn load_project(path: &Path, network: &str) -> Result<Project> {
+    let (dir, dfx_json) = if path.is_dir() {
+        (path.to_path_buf(), path.join("dfx.json"))
+    } else {
+        (
+            path.parent().unwrap_or(Path::new(".")).to_path_buf(),
+            path.to_path_buf(),
+        )
+    };
+    let dfx = read_json(&dfx_json)?.ok_or_else(|| anyhow!("Cannot find {dfx_json:?}"))?;
+    let mut networks = BTreeMap::new();
+    let global = read_json(&config_dir().join("networks.json"))?;
+    for config in [global.as_ref(), dfx.get("networks")].into_iter().flatten() {
+        if let Some(config) = config.as_object() {
+            for (name, network) in config {
+                if let Some(url) = network_url(network) {
+                    networks.insert(name.clone(), url);
+                }
+            }
+        }
+    }
+    // Ids in .dfx/<network> take precedence over canister_ids.json
+    let mut ids = BTreeMap::new();
+    for file in [
+        dir.join("canister_ids.json"),
+        dir.join(".dfx").join(network).join("canister_ids.json"),
+    ] {
+        if let Some(Value::Object(map)) = read_json(&file)? {
+            for (name, v) in map {
+                if let Some(id) = v.get(network).and_then(Value::as_str) {
+                    ids.insert(name, id.to_string());
+                }
+            }
+        }
+    }
+    let mut canisters = Vec::new();
+    let configs = dfx.get("canisters").and_then(Value::as_object);
+    for (name, config) in configs.into_iter().flatten() {
+        let remote = config
+            .pointer(&format!("/remote/id/{network}"))
+            .and_then(Value::as_str);
+        let Some(id) = remote.or(ids.get(name).map(String::as_str)) else {
+            continue;
+        };
+        let id = Principal::from_text(id).with_context(|| format!("Invalid id for {name}"))?;
+        let generated = dir.join(".dfx").join(network).join("canisters").join(name);
+        let did = [
+            config
+                .pointer("/remote/candid")
+                .or(config.get("candid"))
+                .and_then(Value::as_str)
+                .map(|did| dir.join(did)),
+            Some(generated.join(format!("{name}.did"))),
+            Some(generated.join("service.did")),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|path| path.exists());
+        canisters.push(ProjectCanister {
+            name: name.clone(),
+            id,
+            did,
+        });
+    }
+    Ok(Project {
+        canisters,
+        networks,
+    })
+}
+
+fn network_url(network: &Value) -> Option<String> {
+    if let Some(url) = network
+        .get("providers")
+        .and_then(|p| p.get(0))
+        .and_then(Value::as_str)
+    {
+        return Some(url.to_string());
+    }
+    let bind = network.get("bind")?.as_str()?;
+    Some(format!("http://{bind}"))
+}
+
+fn read_json(path: &Path) -> Result<Option<Value>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let json = std::fs::read_to_string(path)?;
+    Ok(Some(
+        serde_json::from_str(&json).with_context(|| format!("Cannot parse {path:?}"))?,
+    ))
+}
+
+#[test]
+fn test_load_project() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("ic-repl-dfx-{}", std::process::id()));
+    let generated = dir.join(".dfx/local/canisters/backend");
+    std::fs::create_dir_all(&generated)?;
+    std::fs::write(
+        dir.join("dfx.json"),
+        r#"{ "canisters": {
+             "backend": { "type": "motoko", "main": "main.mo" },
+             "frontend": { "type": "assets" },
+             "ledger": { "type": "custom", "candid": "ledger.did", "remote": { "id": { "ic": "ryjl3-tyaaa-aaaaa-aaaba-cai" } } } },
+           "networks": { "staging": { "providers": ["https://staging.example"] }, "local": { "bind": "127.0.0.1:8080" } } }"#,
+    )?;
+    std::fs::write(
+        dir.join(".dfx/local/canister_ids.json"),
+        r#"{ "backend": { "local": "bkyz2-fmaaa-aaaaa-qaaaq-cai" }, "ledger": { "local": "be2us-64aaa-aaaaa-qaabq-cai" } }"#,
+    )?;
+    std::fs::write(
+        dir.join("canister_ids.json"),
+        r#"{ "backend": { "ic": "rrkah-fqaaa-aaaaa-aaaaq-cai" } }"#,
+    )?;
+    std::fs::write(generated.join("backend.did"), "service : {}")?;
+    std::fs::write(dir.join("ledger.did"), "service : {}")?;
+    let local = load_project(&dir, "local")?;
+    let names: Vec<_> = local.canisters.iter().map(|c| c.name.as_str()).collect();
+    assert_eq!(names, ["backend", "ledger"]);
+    assert_eq!(local.canisters[0].did, Some(generated.join("backend.did")));
+    assert_eq!(local.canisters[1].did, Some(dir.join("ledger.did")));
+    assert_eq!(local.networks["local"], "http://127.0.0.1:8080");
+    assert_eq!(local.networks["staging"], "https://staging.example");
+    let ic = load_project(&dir.join("dfx.json"), "ic")?;
+    let ids: Vec<_> = ic.canisters.iter().map(|c| c.id.to_text()).collect();
+    assert_eq!(
+        ids,
+        ["rrkah-fqaaa-aaaaa-aaaaq-cai", "ryjl3-tyaaa-aaaaa-aaaba-cai"]
+    );
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
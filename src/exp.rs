@@ -13,8 +13,13 @@ use candid::{
     utils::check_unique,
     Principal, TypeEnv,
 };
-use futures::future::try_join_all;
+use futures::future::LocalBoxFuture;
+use ic_agent::{
+    agent::{CallResponse, UpdateBuilder},
+    Agent, RequestId,
+};
 use std::collections::BTreeMap;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub enum Exp {
@@ -24,9 +29,15 @@ pub enum Exp {
         method: Option<Method>,
         args: Option<Vec<Exp>>,
         mode: CallMode,
+        opts: Vec<(String, Exp)>,
+    },
+    CallFunc {
+        func: Box<Exp>,
+        args: Vec<Exp>,
     },
     ParCall {
         calls: Vec<FuncCall>,
+        opts: Vec<(String, Exp)>,
     },
     Decode {
         method: Option<Method>,
@@ -59,11 +70,13 @@ pub enum CallMode {
     Call,
     Encode,
     Proxy(String),
+    Submit,
 }
 #[derive(Debug, Clone)]
 pub struct FuncCall {
     pub method: Method,
     pub args: Vec<Exp>,
+    pub proxy: Option<String>,
 }
 #[derive(Debug, Clone)]
 pub struct Field {
@@ -78,10 +91,18 @@ impl Exp {
             Exp::Call {
                 mode: CallMode::Call,
                 ..
-            }
+            } | Exp::CallFunc { .. }
         )
     }
     pub fn eval(self, helper: &MyHelper) -> Result<IDLValue> {
+        helper.block_on(self.eval_async(helper))
+    }
+    /// Evaluate the expression. Canister calls are awaited, so that `par_map` can run several
+    /// evaluations concurrently on the shared runtime.
+    pub fn eval_async(self, helper: &MyHelper) -> LocalBoxFuture<'_, Result<IDLValue>> {
+        Box::pin(self.eval_inner(helper))
+    }
+    async fn eval_inner(self, helper: &MyHelper) -> Result<IDLValue> {
         Ok(match self {
             Exp::Path(id, path) => {
                 let v = helper
@@ -93,10 +114,10 @@ impl Exp {
                 project(helper, v, path)?
             }
             Exp::AnnVal(v, ty) => {
-                let arg = v.eval(helper)?;
+                let arg = v.eval_async(helper).await?;
                 cast_type(arg, &ty).with_context(|| format!("casting to type {ty} fails"))?
             }
-            Exp::Fail(v) => match v.eval(helper) {
+            Exp::Fail(v) => match v.eval_async(helper).await {
                 Err(e) => IDLValue::Text(e.to_string()),
                 Ok(_) => return Err(anyhow!("Expects an error state")),
             },
@@ -111,9 +132,9 @@ impl Exp {
                                 "ite expects a bool, true branch and false branch"
                             ));
                         }
-                        return Ok(match exps[0].clone().eval(helper)? {
-                            IDLValue::Bool(true) => exps[1].clone().eval(helper)?,
-                            IDLValue::Bool(false) => exps[2].clone().eval(helper)?,
+                        return Ok(match exps[0].clone().eval_async(helper).await? {
+                            IDLValue::Bool(true) => exps[1].clone().eval_async(helper).await?,
+                            IDLValue::Bool(false) => exps[2].clone().eval_async(helper).await?,
                             _ => {
                                 return Err(anyhow!(
                                     "ite expects the first argument to be a boolean expression"
@@ -125,17 +146,137 @@ impl Exp {
                         if exps.len() != 1 {
                             return Err(anyhow!("exist expects an expression"));
                         }
-                        return Ok(match exps[0].clone().eval(helper) {
+                        return Ok(match exps[0].clone().eval_async(helper).await {
                             Ok(_) => IDLValue::Bool(true),
                             Err(_) => IDLValue::Bool(false),
                         });
                     }
+                    "try_call" => {
+                        use crate::utils::{get_reject, reject_to_value, variant};
+                        if exps.len() != 1 {
+                            return Err(anyhow!("try_call expects an expression"));
+                        }
+                        return match exps[0].clone().eval_async(helper).await {
+                            Ok(v) => Ok(variant("Ok", v)),
+                            Err(e) => match get_reject(&e) {
+                                Some(reject) => Ok(variant("Reject", reject_to_value(reject))),
+                                None => Err(e),
+                            },
+                        };
+                    }
+                    "submit" => {
+                        let [Exp::Call {
+                            method: Some(method),
+                            args,
+                            mode: CallMode::Call,
+                            opts,
+                        }] = exps.as_slice()
+                        else {
+                            return Err(anyhow!("submit expects a canister call"));
+                        };
+                        return Exp::Call {
+                            method: Some(method.clone()),
+                            args: args.clone(),
+                            mode: CallMode::Submit,
+                            opts: opts.clone(),
+                        }
+                        .eval_async(helper)
+                        .await;
+                    }
+                    "retry" => {
+                        let usage = "retry expects (max attempts, function name)";
+                        let [n, Exp::Path(func, path)] = exps.as_slice() else {
+                            return Err(anyhow!(usage));
+                        };
+                        if !path.is_empty() {
+                            return Err(anyhow!(usage));
+                        }
+                        let n = as_u32(&n.clone().eval_async(helper).await?)?.max(1);
+                        let mut backoff = RETRY_INITIAL_BACKOFF;
+                        let mut attempt = 1;
+                        loop {
+                            match apply_func_async(helper, func, Vec::new()).await {
+                                Err(e) if attempt < n && crate::utils::is_transient(&e) => {
+                                    eprintln!(
+                                        "Attempt {attempt} failed, retrying in {} ms: {e}",
+                                        backoff.as_millis()
+                                    );
+                                    tokio::time::sleep(backoff).await;
+                                    backoff = (backoff * 2).min(RETRY_MAX_BACKOFF);
+                                    attempt += 1;
+                                }
+                                res => return res,
+                            }
+                        }
+                    }
+                    "wait_until" => {
+                        use crate::utils::as_u64;
+                        let usage =
+                            "wait_until expects (function name, timeout) and optionally an interval, in milliseconds";
+                        let (Some(Exp::Path(func, path)), 2..=3) = (exps.first(), exps.len())
+                        else {
+                            return Err(anyhow!(usage));
+                        };
+                        if !path.is_empty() {
+                            return Err(anyhow!(usage));
+                        }
+                        let timeout = Duration::from_millis(as_u64(
+                            &exps[1].clone().eval_async(helper).await?,
+                        )?);
+                        let interval = match exps.get(2) {
+                            Some(e) => {
+                                Duration::from_millis(as_u64(&e.clone().eval_async(helper).await?)?)
+                            }
+                            None => Duration::from_secs(1),
+                        };
+                        let start = std::time::Instant::now();
+                        loop {
+                            let v = apply_func_async(helper, func, Vec::new()).await?;
+                            if v == IDLValue::Bool(true) {
+                                return Ok(v);
+                            }
+                            if start.elapsed() + interval > timeout {
+                                return Err(anyhow!(
+                                    "wait_until timed out after {} ms, last value: {v}",
+                                    timeout.as_millis()
+                                ));
+                            }
+                            tokio::time::sleep(interval).await;
+                        }
+                    }
+                    "safe_upgrade" => {
+                        let usage = "safe_upgrade expects (canister_id, wasm module, check function) with optional upgrade options before the check function";
+                        let (Some(Exp::Path(check, path)), 3..=4) = (exps.last(), exps.len())
+                        else {
+                            return Err(anyhow!(usage));
+                        };
+                        if !path.is_empty() {
+                            return Err(anyhow!(usage));
+                        }
+                        let mut args = Vec::with_capacity(exps.len() - 1);
+                        for e in &exps[..exps.len() - 1] {
+                            args.push(e.clone().eval_async(helper).await?);
+                        }
+                        return match args.as_slice() {
+                            [IDLValue::Principal(id), IDLValue::Blob(wasm), ..] => {
+                                let snapshot = crate::management::safe_upgrade(
+                                    helper,
+                                    *id,
+                                    wasm,
+                                    args.get(2),
+                                    check,
+                                )?;
+                                Ok(IDLValue::Blob(snapshot))
+                            }
+                            _ => Err(anyhow!(usage)),
+                        };
+                    }
                     "export" => {
                         use std::io::{BufWriter, Write};
                         if exps.len() <= 1 {
                             return Err(anyhow!("export expects at least two arguments"));
                         }
-                        let path = exps[0].clone().eval(helper)?;
+                        let path = exps[0].clone().eval_async(helper).await?;
                         let IDLValue::Text(path) = path else {
                             return Err(anyhow!("export expects first argument to be a file path"));
                         };
@@ -146,7 +287,7 @@ impl Exp {
                             let Exp::Path(id, _) = arg else {
                                 return Err(anyhow!("export expects variables"));
                             };
-                            let val = arg.clone().eval(helper)?;
+                            let val = arg.clone().eval_async(helper).await?;
                             writeln!(&mut writer, "let {id} = {val};")?;
                         }
                         return Ok(IDLValue::Null);
@@ -156,7 +297,7 @@ impl Exp {
 
                 let mut args = Vec::new();
                 for e in exps.into_iter() {
-                    args.push(e.eval(helper)?);
+                    args.push(e.eval_async(helper).await?);
                 }
                 match func.as_str() {
                     "account" => match args.as_slice() {
@@ -205,12 +346,12 @@ impl Exp {
                         match args.as_slice() {
                             [IDLValue::Text(_), ..] => {
                                 let path = parse_state_path(args.as_slice())?;
-                                fetch_state_path(&helper.agent, path)?
+                                fetch_state_path(&helper.agent, path, helper.call_log.as_ref()).await?
                             }
                             [IDLValue::Principal(effective), IDLValue::Text(_), ..] => {
                                 let mut path = parse_state_path(&args[1..])?;
                                 path.effective_id = Some(*effective);
-                                fetch_state_path(&helper.agent, path)?
+                                fetch_state_path(&helper.agent, path, helper.call_log.as_ref()).await?
                             }
                             _ => {
                                 return Err(anyhow!(
@@ -229,15 +370,106 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("file expects file path")),
                     },
-                    "gzip" => match args.as_slice() {
-                        [IDLValue::Blob(blob)] => {
-                            use libflate::gzip::Encoder;
-                            use std::io::Write;
-                            let mut encoder = Encoder::new(Vec::with_capacity(blob.len()))?;
-                            encoder.write_all(blob)?;
-                            let result = encoder.finish().into_result()?;
-                            IDLValue::Blob(result)
+                    "generate_key" => match args.as_slice() {
+                        [IDLValue::Text(key_type), IDLValue::Text(file), rest @ ..] => {
+                            let password = match rest {
+                                [] => None,
+                                [IDLValue::Text(password)] => Some(password.as_str()),
+                                _ => return Err(anyhow!("generate_key expects password as text")),
+                            };
+                            let path = resolve_path(&helper.base_path, file);
+                            let (pem, identity) =
+                                crate::identity::generate_pem(key_type, password)?;
+                            let mut options = std::fs::OpenOptions::new();
+                            options.write(true).create_new(true);
+                            // the private key is only readable by the owner
+                            #[cfg(unix)]
+                            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                            let mut file = options
+                                .open(&path)
+                                .with_context(|| format!("Cannot create {path:?}"))?;
+                            std::io::Write::write_all(&mut file, pem.as_bytes())?;
+                            IDLValue::Principal(identity.sender().map_err(|e| anyhow!("{e}"))?)
+                        }
+                        _ => return Err(anyhow!("generate_key expects key type and file path")),
+                    },
+                    "identities" => match args.as_slice() {
+                        [] => {
+                            let mut res = Vec::new();
+                            for (name, identity) in helper.identity_map.0.iter() {
+                                let principal = identity.sender().map_err(|e| anyhow!("{e}"))?;
+                                let account = AccountIdentifier::new(principal, None);
+                                res.push(crate::utils::record(vec![
+                                    ("name", IDLValue::Text(name.clone())),
+                                    ("id", IDLValue::Principal(principal)),
+                                    ("account", IDLValue::Blob(account.to_vec())),
+                                    ("current", IDLValue::Bool(*name == helper.current_identity)),
+                                ]));
+                            }
+                            IDLValue::Vec(res)
+                        }
+                        _ => return Err(anyhow!("identities expects no arguments")),
+                    },
+                    "hsm_slots" => match args.as_slice() {
+                        [] => crate::hsm::list_slots(&crate::hsm::default_lib_path())?,
+                        [IDLValue::Text(lib_path)] => {
+                            let lib_path = resolve_path(&helper.base_path, lib_path);
+                            crate::hsm::list_slots(&lib_path.to_string_lossy())?
+                        }
+                        _ => return Err(anyhow!("hsm_slots expects an optional PKCS#11 library path")),
+                    },
+                    "sign" => match args.as_slice() {
+                        [id, IDLValue::Blob(message)] => {
+                            let identity = helper.find_identity(id).context("sign")?;
+                            let sig = identity
+                                .sign_arbitrary(message)
+                                .map_err(|e| anyhow!("{e}"))?;
+                            let (Some(signature), Some(public_key)) = (sig.signature, sig.public_key)
+                            else {
+                                return Err(anyhow!("identity {id} cannot sign"));
+                            };
+                            crate::utils::record(vec![
+                                ("signature", IDLValue::Blob(signature)),
+                                ("public_key", IDLValue::Blob(public_key)),
+                            ])
+                        }
+                        _ => return Err(anyhow!("sign expects an identity and a blob")),
+                    },
+                    "verify" => match args.as_slice() {
+                        [IDLValue::Blob(message), signature, key] => {
+                            let (signature, signed_key) = match signature {
+                                IDLValue::Blob(sig) => (sig.clone(), None),
+                                IDLValue::Record(fs) => {
+                                    let field = |name| {
+                                        get_field(fs, name)
+                                            .and_then(crate::utils::as_blob)
+                                            .ok_or_else(|| anyhow!("verify expects a record returned by sign"))
+                                    };
+                                    (field("signature")?, Some(field("public_key")?))
+                                }
+                                _ => return Err(anyhow!("verify expects a signature blob or a record returned by sign")),
+                            };
+                            let public_key = match (key, signed_key) {
+                                (IDLValue::Blob(key), _) => key.clone(),
+                                (IDLValue::Principal(id), Some(key)) => {
+                                    if Principal::self_authenticating(&key) != *id {
+                                        return Ok(IDLValue::Bool(false));
+                                    }
+                                    key
+                                }
+                                (IDLValue::Principal(_), None) => return Err(anyhow!("verify needs the record returned by sign to check against a principal")),
+                                _ => return Err(anyhow!("verify expects a DER public key or a principal")),
+                            };
+                            IDLValue::Bool(crate::identity::verify(&public_key, message, &signature)?)
                         }
+                        _ => return Err(anyhow!("verify expects a blob, a signature and a public key or principal")),
+                    },
+                    "self_authenticating" => match args.as_slice() {
+                        [IDLValue::Blob(key)] => IDLValue::Principal(Principal::self_authenticating(key)),
+                        _ => return Err(anyhow!("self_authenticating expects a DER public key")),
+                    },
+                    "gzip" => match args.as_slice() {
+                        [IDLValue::Blob(blob)] => IDLValue::Blob(crate::utils::gzip(blob)?),
                         _ => return Err(anyhow!("gzip expects blob")),
                     },
                     "exec" => match args.as_slice() {
@@ -325,10 +557,18 @@ impl Exp {
                     },
                     "send" if helper.offline.is_none() => match args.as_slice() {
                         [IDLValue::Blob(blob)] => {
-                            use crate::offline::{send, send_messages};
+                            use crate::offline::{send, send_messages, Messages};
                             let json = std::str::from_utf8(blob)?;
                             let res = match json.trim_start().chars().next() {
-                                Some('{') => send(helper, &serde_json::from_str(json)?)?,
+                                Some('{') => match serde_json::from_str::<Messages>(json) {
+                                    Ok(msgs) => send_messages(helper, &msgs)?,
+                                    Err(msgs_err) => match serde_json::from_str(json) {
+                                        Ok(msg) => send(helper, &msg)?,
+                                        Err(msg_err) => {
+                                            return Err(anyhow!("not a valid json message. As messages: {msgs_err}. As a single message: {msg_err}"))
+                                        }
+                                    },
+                                },
                                 Some('[') => send_messages(helper, &serde_json::from_str(json)?)?,
                                 _ => return Err(anyhow!("not a valid json message")),
                             };
@@ -407,14 +647,138 @@ impl Exp {
                             ))
                         }
                     },
+                    "install" | "upgrade" | "reinstall" => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Blob(wasm), IDLValue::Blob(arg), ..]
+                            if func == "install" && args.len() <= 4 =>
+                        {
+                            use crate::management::{install, install_mode};
+                            let mode = install_mode(args.get(3))?;
+                            IDLValue::Blob(install(helper, *id, wasm, arg, mode, true)?)
+                        }
+                        [IDLValue::Principal(id), IDLValue::Blob(wasm)]
+                        | [IDLValue::Principal(id), IDLValue::Blob(wasm), IDLValue::Record(_)] => {
+                            use crate::management::install_canister;
+                            IDLValue::Blob(install_canister(helper, *id, wasm, &func, args.get(2))?)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "{func} expects (canister_id, wasm module) and optionally a record for options"
+                            ))
+                        }
+                    },
+                    "assert_upgrade" => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Blob(wasm)] => {
+                            crate::management::assert_upgrade(helper, *id, wasm)?;
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("assert_upgrade expects (canister_id, wasm module)")),
+                    },
+                    "list_snapshots" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => crate::management::list_snapshots(helper, *id)?,
+                        _ => return Err(anyhow!("list_snapshots expects canister_id")),
+                    },
+                    "logs" => match args.as_slice() {
+                        [IDLValue::Principal(id)] | [IDLValue::Principal(id), IDLValue::Record(_)] => {
+                            crate::management::logs(helper, *id, args.get(1))?
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "logs expects canister_id and optionally a record for options"
+                            ))
+                        }
+                    },
+                    "sleep" => match args.as_slice() {
+                        [ms] => {
+                            let ms = crate::utils::as_u64(ms)?;
+                            tokio::time::sleep(Duration::from_millis(ms)).await;
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("sleep expects the duration in milliseconds")),
+                    },
+                    "raw_call" => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Text(method), arg, IDLValue::Text(mode)]
+                            if matches!(mode.as_str(), "query" | "update") =>
+                        {
+                            let arg = crate::utils::as_blob(arg)
+                                .ok_or_else(|| anyhow!("raw_call expects a blob as argument"))?;
+                            let is_query = mode == "query";
+                            match raw_call(helper, id, method, &arg, is_query).await? {
+                                Some(reply) => IDLValue::Blob(reply),
+                                None => IDLValue::Null,
+                            }
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "raw_call expects (canister_id, method name, arg blob, \"query\" or \"update\")"
+                            ))
+                        }
+                    },
+                    "request_status" | "wait" if helper.offline.is_some() => {
+                        return Err(anyhow!("{func} is not supported in offline mode"))
+                    }
+                    "request_status" => match args.as_slice() {
+                        [IDLValue::Record(fs)] => {
+                            let req = SubmittedRequest::from_fields(helper, fs)?;
+                            let network = req.network.clone();
+                            helper
+                                .on_network(&network, move |helper| {
+                                    Box::pin(async move { request_status(helper, &req).await })
+                                })
+                                .await?
+                        }
+                        _ => return Err(anyhow!("request_status expects a submitted request")),
+                    },
+                    "wait" => match args.as_slice() {
+                        [IDLValue::Record(fs), timeout] => {
+                            let req = SubmittedRequest::from_fields(helper, fs)?;
+                            let timeout = crate::utils::as_u64(timeout)?;
+                            let network = req.network.clone();
+                            let res = helper
+                                .on_network(&network, move |helper| {
+                                    Box::pin(
+                                        async move { wait_request(helper, &req, timeout).await },
+                                    )
+                                })
+                                .await?;
+                            args_to_value(res)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "wait expects a submitted request and a timeout in milliseconds"
+                            ))
+                        }
+                    },
+                    "fetch_blocks" => match args.as_slice() {
+                        [reply] => fetch_blocks(helper, reply).await?,
+                        _ => return Err(anyhow!("fetch_blocks expects a ledger reply")),
+                    },
+                    "create_canister" => match args.as_slice() {
+                        [] | [IDLValue::Record(_)] => IDLValue::Principal(
+                            crate::management::create_canister(helper, args.first())?,
+                        ),
+                        _ => {
+                            return Err(anyhow!(
+                                "create_canister expects optionally a record for options"
+                            ))
+                        }
+                    },
+                    "start_canister" | "stop_canister" | "delete_canister" => match args
+                        .as_slice()
+                    {
+                        [IDLValue::Principal(id)] => {
+                            crate::management::set_canister_status(helper, *id, &func)?;
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("{func} expects canister_id")),
+                    },
                     "flamegraph" => match args.as_slice() {
                         [IDLValue::Principal(cid), IDLValue::Text(title), IDLValue::Text(file)] => {
-                            let mut map = helper.canister_map.borrow_mut();
-                            let names = match map.get(&helper.agent, cid) {
-                                Ok(crate::helper::CanisterInfo {
+                            let _ = helper.fetch_canister_info(cid).await;
+                            let names = match helper.canister_map.borrow().0.get(cid) {
+                                Some(crate::helper::CanisterInfo {
                                     profiling: Some(names),
                                     ..
-                                }) => names,
+                                }) => names.clone(),
                                 _ => return Err(anyhow!("{} is not instrumented", cid)),
                             };
                             let mut path = resolve_path(&std::env::current_dir()?, file);
@@ -424,10 +788,11 @@ impl Exp {
                             let cost = crate::profiling::get_profiling(
                                 &helper.agent,
                                 cid,
-                                names,
+                                &names,
                                 title,
                                 path,
-                            )?;
+                            )
+                            .await?;
                             IDLValue::Nat(cost.into())
                         }
                         _ => {
@@ -559,11 +924,11 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("{func} expects two numbers")),
                     },
-                    func => apply_func(helper, func, args)?,
+                    func => apply_func_async(helper, func, args).await?,
                 }
             }
             Exp::Decode { method, blob } => {
-                let blob = blob.eval(helper)?;
+                let blob = blob.eval_async(helper).await?;
                 if *blob.value_ty() != TypeInner::Vec(TypeInner::Nat8.into()) {
                     return Err(anyhow!("not a blob"));
                 }
@@ -580,7 +945,7 @@ impl Exp {
                 };
                 let args = match method {
                     Some(method) => {
-                        let info = method.get_info(helper, false)?;
+                        let info = method.get_info_async(helper, false).await?;
                         if let Some((env, func)) = info.signature {
                             IDLArgs::from_bytes_with_types(&bytes, &env, &func.rets)?
                         } else {
@@ -591,48 +956,43 @@ impl Exp {
                 };
                 args_to_value(args)
             }
-            Exp::ParCall { calls } => {
-                let mut futures = Vec::with_capacity(calls.len());
-                for call in calls {
-                    let mut args = Vec::with_capacity(call.args.len());
-                    for arg in call.args.into_iter() {
-                        args.push(arg.eval(helper)?);
-                    }
-                    let args = IDLArgs { args };
-                    let info = call.method.get_info(helper, false)?;
-                    let bytes = if let Some((env, func)) = &info.signature {
-                        args.to_bytes_with_types(env, &func.args)?
-                    } else {
-                        args.to_bytes()?
-                    };
-                    let method = &call.method.method;
-                    let effective_id = get_effective_canister_id(info.canister_id, method, &bytes)?
-                        .unwrap_or(helper.default_effective_canister_id);
-                    let mut builder = helper.agent.update(&info.canister_id, method);
-                    builder = builder
-                        .with_arg(bytes)
-                        .with_effective_canister_id(effective_id);
-                    let call_future = async move {
-                        let res = builder.call_and_wait().await?;
-                        if let Some((env, func)) = &info.signature {
-                            Ok(IDLArgs::from_bytes_with_types(&res, env, &func.rets)?)
-                        } else {
-                            Ok(IDLArgs::from_bytes(&res)?)
-                        }
+            Exp::ParCall { calls, mut opts } => match take_network(helper, &mut opts).await? {
+                Some(network) => {
+                    helper
+                        .on_network(&network, move |helper| {
+                            Box::pin(par_call(helper, calls, opts))
+                        })
+                        .await?
+                }
+                None => par_call(helper, calls, opts).await?,
+            },
+            Exp::Call {
+                method,
+                args,
+                mode,
+                mut opts,
+            } => {
+                if let Some(network) = take_network(helper, &mut opts).await? {
+                    let exp = Exp::Call {
+                        method,
+                        args,
+                        mode,
+                        opts,
                     };
-                    futures.push(call_future);
+                    return helper
+                        .on_network(&network, move |helper| exp.eval_async(helper))
+                        .await;
                 }
-                let res = parallel_calls(futures)?;
-                let res = IDLArgs {
-                    args: res.into_iter().map(args_to_value).collect(),
-                };
-                args_to_value(res)
-            }
-            Exp::Call { method, args, mode } => {
+                if let (CallMode::Encode, Some((name, _))) = (&mode, opts.first()) {
+                    return Err(anyhow!(
+                        "encode only accepts the network option, found {name}"
+                    ));
+                }
+                let opts = CallOpts::from_exps(helper, opts).await?;
                 let args = if let Some(args) = args {
                     let mut res = Vec::with_capacity(args.len());
                     for arg in args.into_iter() {
-                        res.push(arg.eval(helper)?);
+                        res.push(arg.eval_async(helper).await?);
                     }
                     Some(IDLArgs { args: res })
                 } else {
@@ -640,10 +1000,19 @@ impl Exp {
                 };
                 let opt_info = if let Some(method) = &method {
                     let is_encode = matches!(mode, CallMode::Encode);
-                    Some(method.get_info(helper, is_encode)?)
+                    Some(method.get_info_async(helper, is_encode).await?)
                 } else {
                     None
                 };
+                if let (Some(info), Some(method), Some(args)) = (&opt_info, &method, &args) {
+                    if matches!(mode, CallMode::Call | CallMode::Submit)
+                        && info.canister_id == Principal::management_canister()
+                        && method.method == "install_code"
+                        && !opts.skip_check
+                    {
+                        crate::management::check_install_code(helper, args)?;
+                    }
+                }
                 let bytes = if let Some(MethodInfo {
                     signature: Some((env, func)),
                     ..
@@ -685,7 +1054,7 @@ impl Exp {
                         let info = opt_info.unwrap();
                         let ok_to_profile = ok_to_profile(helper, &info);
                         let before_cost = if ok_to_profile {
-                            get_cycles(&helper.agent, &info.canister_id)?
+                            get_cycles(&helper.agent, &info.canister_id).await?
                         } else {
                             0
                         };
@@ -695,10 +1064,12 @@ impl Exp {
                             &method.method,
                             &bytes,
                             &info.signature,
-                            &helper.offline,
-                        )?;
+                            &opts,
+                        )
+                        .await?;
                         if ok_to_profile {
-                            let cost = get_cycles(&helper.agent, &info.canister_id)? - before_cost;
+                            let cost =
+                                get_cycles(&helper.agent, &info.canister_id).await? - before_cost;
                             println!("Cost: {cost} Wasm instructions");
                             let cost = IDLValue::Record(vec![IDLField {
                                 id: Label::Named("__cost".to_string()),
@@ -710,28 +1081,28 @@ impl Exp {
                             args_to_value(res)
                         }
                     }
+                    CallMode::Submit => {
+                        let method = method.unwrap();
+                        let info = opt_info.unwrap();
+                        submit(
+                            helper,
+                            &info.canister_id,
+                            &method.method,
+                            &bytes,
+                            &info.signature,
+                            &opts,
+                        )
+                        .await?
+                        .to_value()
+                    }
                     CallMode::Proxy(id) => {
                         let method = method.unwrap();
                         let canister_id = str_to_principal(&method.canister, helper)?;
                         let proxy_id = str_to_principal(&id, helper)?;
-                        let mut env = MyHelper::new(
-                            helper.agent.clone(),
-                            helper.agent_url.clone(),
-                            helper.offline.clone(),
-                            helper.verbose,
-                        );
-                        env.canister_map.borrow_mut().0.insert(
-                            proxy_id,
-                            helper
-                                .canister_map
-                                .borrow()
-                                .0
-                                .get(&proxy_id)
-                                .ok_or_else(|| {
-                                    anyhow!("{} canister interface not found", proxy_id)
-                                })?
-                                .clone(),
-                        );
+                        if !helper.canister_map.borrow().0.contains_key(&proxy_id) {
+                            return Err(anyhow!("{} canister interface not found", proxy_id));
+                        }
+                        let mut env = helper.spawn();
                         env.env.0.insert("_msg".to_string(), IDLValue::Blob(bytes));
                         let code = format!(
                             r#"
@@ -757,6 +1128,16 @@ let _ = decode as "{canister}".{method} _.Ok.return;
                     }
                 }
             }
+            Exp::CallFunc { func, args } => {
+                let IDLValue::Func(id, method) = func.eval_async(helper).await? else {
+                    return Err(anyhow!("call expects a func value"));
+                };
+                let mut res = Vec::with_capacity(args.len());
+                for arg in args.into_iter() {
+                    res.push(arg.eval_async(helper).await?);
+                }
+                call_func(helper, id, &method, IDLArgs { args: res }).await?
+            }
             Exp::Bool(b) => IDLValue::Bool(b),
             Exp::Null => IDLValue::Null,
             Exp::Text(s) => IDLValue::Text(s),
@@ -765,12 +1146,12 @@ let _ = decode as "{canister}".{method} _.Ok.return;
             Exp::Principal(id) => IDLValue::Principal(id),
             Exp::Service(id) => IDLValue::Service(id),
             Exp::Func(id, meth) => IDLValue::Func(id, meth),
-            Exp::Opt(v) => IDLValue::Opt(Box::new((*v).eval(helper)?)),
+            Exp::Opt(v) => IDLValue::Opt(Box::new((*v).eval_async(helper).await?)),
             Exp::Blob(b) => IDLValue::Blob(b),
             Exp::Vec(vs) => {
                 let mut vec = Vec::with_capacity(vs.len());
                 for v in vs.into_iter() {
-                    vec.push(v.eval(helper)?);
+                    vec.push(v.eval_async(helper).await?);
                 }
                 IDLValue::Vec(vec)
             }
@@ -779,7 +1160,7 @@ let _ = decode as "{canister}".{method} _.Ok.return;
                 for Field { id, val } in fs.into_iter() {
                     res.push(IDLField {
                         id,
-                        val: val.eval(helper)?,
+                        val: val.eval_async(helper).await?,
                     });
                 }
                 IDLValue::Record(res)
@@ -787,7 +1168,7 @@ let _ = decode as "{canister}".{method} _.Ok.return;
             Exp::Variant(f, idx) => {
                 let f = IDLField {
                     id: f.id,
-                    val: f.val.eval(helper)?,
+                    val: f.val.eval_async(helper).await?,
                 };
                 IDLValue::Variant(VariantValue(Box::new(f), idx))
             }
@@ -811,59 +1192,28 @@ pub struct MethodInfo {
 }
 impl Method {
     pub fn get_info(&self, helper: &MyHelper, is_encode: bool) -> Result<MethodInfo> {
+        helper.block_on(self.get_info_async(helper, is_encode))
+    }
+    pub async fn get_info_async(&self, helper: &MyHelper, is_encode: bool) -> Result<MethodInfo> {
         if is_encode && self.method == "__init_args" {
             if let Some(IDLValue::Blob(bytes)) = helper.env.0.get(&self.canister) {
-                use ic_wasm::{metadata::get_metadata, utils::parse_wasm};
-                let m = parse_wasm(bytes, false)?;
-                let args = get_metadata(&m, "candid:args");
-                let candid = get_metadata(&m, "candid:service");
-                let canister_id = Principal::anonymous();
-                match args {
-                    None => {
-                        eprintln!("Warning: no candid:args metadata in the Wasm module, use types inferred from textual value.");
-                        return Ok(MethodInfo {
-                            canister_id,
-                            signature: None,
-                            profiling: None,
-                        });
-                    }
-                    Some(args) => {
-                        let candid = candid
-                            .as_ref()
-                            .map(|x| std::str::from_utf8(x).unwrap())
-                            .unwrap_or("service : {}");
-                        let (env, ty) = candid_parser::utils::merge_init_args(
-                            candid,
-                            std::str::from_utf8(&args)?,
-                        )?;
-                        let init_args = find_init_args(&env, &ty).expect("invalid init arg types");
-                        let signature = Some((
-                            env,
-                            Function {
-                                args: init_args,
-                                rets: Vec::new(),
-                                modes: Vec::new(),
-                            },
-                        ));
-                        return Ok(MethodInfo {
-                            canister_id,
-                            signature,
-                            profiling: None,
-                        });
-                    }
-                }
+                return Ok(MethodInfo {
+                    canister_id: Principal::anonymous(),
+                    signature: wasm_init_args(bytes)?,
+                    profiling: None,
+                });
             }
         }
         let canister_id = str_to_principal(&self.canister, helper)?;
-        let agent = &helper.agent;
-        let mut map = helper.canister_map.borrow_mut();
-        Ok(match map.get(agent, &canister_id) {
-            Err(_) => MethodInfo {
+        let _ = helper.fetch_canister_info(&canister_id).await;
+        let map = helper.canister_map.borrow();
+        Ok(match map.0.get(&canister_id) {
+            None => MethodInfo {
                 canister_id,
                 signature: None,
                 profiling: None,
             },
-            Ok(info) => {
+            Some(info) => {
                 let signature = if self.method == "__init_args" {
                     eprintln!(
                         "Warning: no init args in did file, use types inferred from textual value."
@@ -902,7 +1252,181 @@ impl Method {
     }
 }
 
+/// Get the init args types from the candid:args metadata of a Wasm module.
+pub fn wasm_init_args(bytes: &[u8]) -> Result<Option<(TypeEnv, Function)>> {
+    use ic_wasm::{metadata::get_metadata, utils::parse_wasm};
+    let bytes = crate::utils::gunzip_if_needed(bytes)?;
+    let m = parse_wasm(&bytes, false)?;
+    let args = get_metadata(&m, "candid:args");
+    let candid = get_metadata(&m, "candid:service");
+    match args {
+        None => {
+            eprintln!("Warning: no candid:args metadata in the Wasm module, use types inferred from textual value.");
+            Ok(None)
+        }
+        Some(args) => {
+            let candid = candid
+                .as_ref()
+                .map(|x| std::str::from_utf8(x))
+                .transpose()?
+                .unwrap_or("service : {}");
+            let (env, ty) =
+                candid_parser::utils::merge_init_args(candid, std::str::from_utf8(&args)?)?;
+            let init_args = find_init_args(&env, &ty)
+                .ok_or_else(|| anyhow!("invalid init arg types in candid:args metadata"))?;
+            Ok(Some((
+                env,
+                Function {
+                    args: init_args,
+                    rets: Vec::new(),
+                    modes: Vec::new(),
+                },
+            )))
+        }
+    }
+}
+
+/// Call a func reference. The types come from the enclosing reply where the func value was seen,
+/// or from the Candid interface of the canister.
+pub async fn call_func(
+    helper: &MyHelper,
+    canister_id: Principal,
+    method: &str,
+    args: IDLArgs,
+) -> Result<IDLValue> {
+    let signature = method_signature(helper, canister_id, method).await?;
+    let bytes = match &signature {
+        Some((env, func)) => args.to_bytes_with_types(env, &func.args)?,
+        None => args.to_bytes()?,
+    };
+    let res = call(
+        helper,
+        &canister_id,
+        method,
+        &bytes,
+        &signature,
+        &CallOpts::default(),
+    )
+    .await?;
+    Ok(args_to_value(res))
+}
+
+/// Find the method type from the func values seen so far, or from the canister interface.
+async fn method_signature(
+    helper: &MyHelper,
+    canister_id: Principal,
+    method: &str,
+) -> Result<Option<(TypeEnv, Function)>> {
+    let known = helper
+        .func_types
+        .borrow()
+        .get(&(canister_id, method.to_string()))
+        .cloned();
+    Ok(match known {
+        Some(sig) => Some(sig),
+        None => {
+            Method {
+                canister: canister_id.to_text(),
+                method: method.to_string(),
+            }
+            .get_info_async(helper, false)
+            .await?
+            .signature
+        }
+    })
+}
+
+/// Fetch the full block range of a ledger `query_blocks`/`get_blocks`/`icrc3_get_blocks` reply by
+/// following the `archived_blocks` callbacks. Archived blocks come before the blocks in the reply.
+async fn fetch_blocks(helper: &MyHelper, reply: &IDLValue) -> Result<IDLValue> {
+    use crate::utils::{as_u64, record};
+    let usage = || anyhow!("fetch_blocks expects a reply with archived_blocks and blocks");
+    let IDLValue::Record(fs) = reply else {
+        return Err(usage());
+    };
+    let (Some(IDLValue::Vec(archives)), Some(IDLValue::Vec(blocks))) =
+        (get_field(fs, "archived_blocks"), get_field(fs, "blocks"))
+    else {
+        return Err(usage());
+    };
+    let get_blocks = |res: IDLValue| -> Result<Vec<IDLValue>> {
+        let res = match res {
+            IDLValue::Variant(v) if v.0.id == Label::Named("Ok".to_string()) => v.0.val,
+            IDLValue::Variant(v) => return Err(anyhow!("archive returns an error: {}", v.0.val)),
+            res => res,
+        };
+        match res {
+            IDLValue::Vec(vs) => Ok(vs),
+            IDLValue::Record(fs) => match get_field(&fs, "blocks") {
+                Some(IDLValue::Vec(vs)) => Ok(vs.clone()),
+                _ => Err(anyhow!("unexpected archive reply")),
+            },
+            // offline mode doesn't return anything
+            IDLValue::Null => Ok(Vec::new()),
+            _ => Err(anyhow!("unexpected archive reply")),
+        }
+    };
+    let mut res = Vec::new();
+    for archive in archives {
+        let IDLValue::Record(fs) = archive else {
+            return Err(usage());
+        };
+        let Some(IDLValue::Func(id, method)) = get_field(fs, "callback") else {
+            return Err(usage());
+        };
+        if let Some(args) = get_field(fs, "args") {
+            // ICRC-3 archives take the ranges as is
+            let reply = call_func(
+                helper,
+                *id,
+                method,
+                IDLArgs {
+                    args: vec![args.clone()],
+                },
+            )
+            .await?;
+            res.extend(get_blocks(reply)?);
+            continue;
+        }
+        let (Some(start), Some(length)) = (get_field(fs, "start"), get_field(fs, "length")) else {
+            return Err(usage());
+        };
+        let (start_idx, length) = (as_u64(start)?, as_u64(length)?);
+        // Keep the number types of the reply
+        let number = |like: &IDLValue, n: u64| match like {
+            IDLValue::Nat64(_) => IDLValue::Nat64(n),
+            _ => IDLValue::Nat(n.into()),
+        };
+        let mut fetched = 0;
+        while fetched < length {
+            let args = record(vec![
+                ("start", number(start, start_idx + fetched)),
+                ("length", number(start, length - fetched)),
+            ]);
+            let blocks =
+                get_blocks(call_func(helper, *id, method, IDLArgs { args: vec![args] }).await?)?;
+            if blocks.is_empty() {
+                break;
+            }
+            fetched += blocks.len() as u64;
+            res.extend(blocks);
+        }
+    }
+    res.extend(blocks.iter().cloned());
+    Ok(IDLValue::Vec(res))
+}
+
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 pub fn apply_func(helper: &MyHelper, func: &str, args: Vec<IDLValue>) -> Result<IDLValue> {
+    helper.block_on(apply_func_async(helper, func, args))
+}
+pub async fn apply_func_async(
+    helper: &MyHelper,
+    func: &str,
+    args: Vec<IDLValue>,
+) -> Result<IDLValue> {
     match helper.func_env.0.get(func) {
         None => Err(anyhow!("Unknown function {}", func)),
         Some((formal_args, body)) => {
@@ -919,42 +1443,385 @@ pub fn apply_func(helper: &MyHelper, func: &str, args: Vec<IDLValue>) -> Result<
                 helper.env.0.insert(id.to_string(), v);
             }
             for cmd in body.iter() {
-                cmd.clone().run(&mut helper)?;
+                cmd.clone().run_async(&mut helper).await?;
             }
             let res = helper.env.0.get("_").unwrap_or(&IDLValue::Null).clone();
             Ok(res)
         }
     }
 }
-#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+/// Remove the `network` option, which selects the network to evaluate the call on.
+async fn take_network(helper: &MyHelper, opts: &mut Vec<(String, Exp)>) -> Result<Option<String>> {
+    let Some(i) = opts.iter().position(|(name, _)| name == "network") else {
+        return Ok(None);
+    };
+    match opts.remove(i).1.eval_async(helper).await? {
+        IDLValue::Text(network) => Ok(Some(network)),
+        _ => Err(anyhow!("network expects a network name")),
+    }
+}
+/// Options from `call with { ... }`, also accepted by `submit` and `par_call`. `encode` only takes
+/// the `network` option, which is removed before these are parsed.
+#[derive(Default)]
+pub struct CallOpts {
+    /// The agent with the identity from the `identity` option
+    agent: Option<Agent>,
+    identity: Option<std::sync::Arc<dyn ic_agent::Identity>>,
+    effective_canister_id: Option<Principal>,
+    ingress_expiry: Option<Duration>,
+    timeout: Option<Duration>,
+    query: Option<bool>,
+    /// Skip the Candid interface check of `install_code` upgrades
+    skip_check: bool,
+}
+impl CallOpts {
+    async fn from_exps(helper: &MyHelper, opts: Vec<(String, Exp)>) -> Result<Self> {
+        let mut res = CallOpts::default();
+        for (name, e) in opts {
+            if !res.set(helper, &name, e.eval_async(helper).await?)? {
+                return Err(anyhow!("Unknown call option {name}, expect network, identity, effective_canister_id, ingress_expiry, timeout, query or skip_check"));
+            }
+        }
+        Ok(res)
+    }
+    /// Set an option, and return false if the option name is unknown.
+    fn set(&mut self, helper: &MyHelper, name: &str, v: IDLValue) -> Result<bool> {
+        use crate::utils::as_u64;
+        match (name, v) {
+            ("identity", v) => {
+                let identity = helper
+                    .find_identity(&v)
+                    .with_context(|| "identity option".to_string())?;
+                let mut agent = helper.agent.clone();
+                agent.set_arc_identity(identity.clone());
+                self.agent = Some(agent);
+                self.identity = Some(identity);
+            }
+            ("effective_canister_id", IDLValue::Principal(id)) => {
+                self.effective_canister_id = Some(id)
+            }
+            ("ingress_expiry", v) => self.ingress_expiry = Some(Duration::from_millis(as_u64(&v)?)),
+            ("timeout", v) => self.timeout = Some(Duration::from_millis(as_u64(&v)?)),
+            ("query", IDLValue::Bool(b)) => self.query = Some(b),
+            ("skip_check", IDLValue::Bool(b)) => self.skip_check = b,
+            ("effective_canister_id" | "query" | "skip_check", v) => {
+                return Err(anyhow!("Unexpected value {v} for call option {name}"))
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+    fn agent<'a>(&'a self, helper: &'a MyHelper) -> &'a Agent {
+        self.agent.as_ref().unwrap_or(&helper.agent)
+    }
+    /// Check that the delegation targets of the signing identity include `canister_id`.
+    fn check_target(&self, helper: &MyHelper, canister_id: &Principal) -> Result<()> {
+        let identity = match &self.identity {
+            Some(identity) => Some(identity),
+            None => helper.identity_map.0.get(&helper.current_identity),
+        };
+        match identity {
+            Some(identity) => {
+                crate::identity::check_target(&identity.delegation_chain(), canister_id)
+            }
+            None => Ok(()),
+        }
+    }
+    fn effective_canister_id(
+        &self,
+        helper: &MyHelper,
+        canister_id: &Principal,
+        method: &str,
+        args: &[u8],
+    ) -> Result<Principal> {
+        Ok(match self.effective_canister_id {
+            Some(id) => id,
+            None => get_effective_canister_id(*canister_id, method, args)?
+                .unwrap_or(helper.default_effective_canister_id),
+        })
+    }
+}
+/// A call in par_call with its arguments encoded.
+struct ParCallItem {
+    info: MethodInfo,
+    method: String,
+    bytes: Vec<u8>,
+    /// The proxy canister and the signature of its `wallet_call` method
+    proxy: Option<(Principal, Option<(TypeEnv, Function)>)>,
+    before_cost: Option<i64>,
+}
+
+async fn par_call(
+    helper: &MyHelper,
+    calls: Vec<FuncCall>,
+    opts: Vec<(String, Exp)>,
+) -> Result<IDLValue> {
+    use crate::profiling::{get_cycles, ok_to_profile};
+    use crate::utils::variant;
+    let mut collect = false;
+    let mut limit = None;
+    let mut call_opts = CallOpts::default();
+    for (name, e) in opts {
+        match (name.as_str(), e.eval_async(helper).await?) {
+            ("collect", IDLValue::Bool(b)) => collect = b,
+            ("limit", v) if as_u32(&v).is_ok_and(|n| n > 0) => limit = Some(as_u32(&v)? as usize),
+            (name, v) => {
+                if !call_opts.set(helper, name, v)? {
+                    return Err(anyhow!(
+                        "par_call only supports with {{ collect = <bool>; limit = <nat> }} and call options"
+                    ));
+                }
+            }
+        }
+    }
+    let mut items = Vec::with_capacity(calls.len());
+    for call in calls {
+        let mut args = Vec::with_capacity(call.args.len());
+        for arg in call.args.into_iter() {
+            args.push(arg.eval_async(helper).await?);
+        }
+        let args = IDLArgs { args };
+        let info = call.method.get_info_async(helper, false).await?;
+        let bytes = if let Some((env, func)) = &info.signature {
+            args.to_bytes_with_types(env, &func.args)?
+        } else {
+            args.to_bytes()?
+        };
+        let proxy = match call.proxy {
+            Some(proxy) => {
+                let wallet_call = Method {
+                    canister: proxy,
+                    method: "wallet_call".to_string(),
+                }
+                .get_info_async(helper, false)
+                .await?;
+                if wallet_call.signature.is_none() {
+                    return Err(anyhow!(
+                        "{} canister interface not found",
+                        wallet_call.canister_id
+                    ));
+                }
+                Some((wallet_call.canister_id, wallet_call.signature))
+            }
+            None => None,
+        };
+        // Profiling is skipped for a canister whose cycles cannot be read
+        let before_cost = if proxy.is_none() && ok_to_profile(helper, &info) {
+            get_cycles(&helper.agent, &info.canister_id).await.ok()
+        } else {
+            None
+        };
+        items.push(ParCallItem {
+            info,
+            method: call.method.method,
+            bytes,
+            proxy,
+            before_cost,
+        });
+    }
+    let futures = items
+        .iter()
+        .map(|item| par_call_item(helper, item, &call_opts));
+    let limit = limit.unwrap_or(items.len()).max(1);
+    let res = parallel_calls(futures, limit, collect).await?;
+    let mut values = Vec::with_capacity(res.len());
+    for (item, res) in items.iter().zip(res) {
+        let res = match res {
+            Ok(res) => res,
+            Err(e) if collect => {
+                values.push(variant("Err", IDLValue::Text(e.to_string())));
+                continue;
+            }
+            // parallel_calls only returns errors in collect mode
+            Err(e) => return Err(e),
+        };
+        let cost = match item.before_cost {
+            Some(before_cost) => get_cycles(&helper.agent, &item.info.canister_id)
+                .await
+                .ok()
+                .map(|after| after - before_cost),
+            None => None,
+        };
+        let res = match cost {
+            Some(cost) => {
+                let cost = IDLValue::Record(vec![IDLField {
+                    id: Label::Named("__cost".to_string()),
+                    val: IDLValue::Int64(cost),
+                }]);
+                args_to_value(IDLArgs::new(&[args_to_value(res), cost]))
+            }
+            None => args_to_value(res),
+        };
+        values.push(if collect { variant("Ok", res) } else { res });
+    }
+    Ok(args_to_value(IDLArgs { args: values }))
+}
+
+async fn par_call_item(
+    helper: &MyHelper,
+    item: &ParCallItem,
+    opts: &CallOpts,
+) -> anyhow::Result<IDLArgs> {
+    let canister_id = &item.info.canister_id;
+    let signature = &item.info.signature;
+    let Some((proxy_id, wallet_call)) = &item.proxy else {
+        let is_query = opts
+            .query
+            .unwrap_or_else(|| signature.as_ref().is_some_and(|(_, f)| f.is_query()));
+        let bytes = send_call(
+            helper,
+            canister_id,
+            &item.method,
+            &item.bytes,
+            is_query,
+            signature,
+            opts,
+        )
+        .await?;
+        return match bytes {
+            Some(bytes) => decode_reply(helper, &bytes, signature),
+            None => Ok(IDLArgs::new(&[])),
+        };
+    };
+    use crate::utils::record;
+    let (env, func) = wallet_call.as_ref().unwrap();
+    let args = IDLArgs::new(&[record(vec![
+        ("args", IDLValue::Blob(item.bytes.clone())),
+        ("cycles", IDLValue::Number("0".to_string())),
+        ("method_name", IDLValue::Text(item.method.clone())),
+        ("canister", IDLValue::Principal(*canister_id)),
+    ])])
+    .to_bytes_with_types(env, &func.args)?;
+    let bytes = send_call(
+        helper,
+        proxy_id,
+        "wallet_call",
+        &args,
+        false,
+        wallet_call,
+        opts,
+    )
+    .await?;
+    let Some(bytes) = bytes else {
+        return Ok(IDLArgs::new(&[]));
+    };
+    let reply = IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?;
+    let reply = match reply.args.first() {
+        Some(IDLValue::Variant(v)) if v.0.id == Label::Named("Ok".to_string()) => match &v.0.val {
+            IDLValue::Record(fs) => get_field(fs, "return").and_then(crate::utils::as_blob),
+            _ => None,
+        },
+        Some(IDLValue::Variant(v)) => return Err(anyhow!("wallet_call fails: {}", v.0.val)),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("unexpected reply from wallet_call"))?;
+    decode_reply(helper, &reply, signature)
+}
+
+/// Run the calls with at most `limit` calls in flight, and keep the results in order.
+/// Unless `collect` is true, the first error is returned.
 async fn parallel_calls(
-    futures: Vec<impl std::future::Future<Output = anyhow::Result<IDLArgs>>>,
-) -> anyhow::Result<Vec<IDLArgs>> {
-    let res = try_join_all(futures).await?;
+    futures: impl Iterator<Item = impl std::future::Future<Output = anyhow::Result<IDLArgs>>>,
+    limit: usize,
+    collect: bool,
+) -> anyhow::Result<Vec<anyhow::Result<IDLArgs>>> {
+    use futures::stream::{self, StreamExt};
+    let mut stream = stream::iter(futures).buffered(limit);
+    let mut res = Vec::new();
+    while let Some(r) = stream.next().await {
+        res.push(if collect { r } else { Ok(r?) });
+    }
     Ok(res)
 }
-#[tokio::main]
 async fn call(
     helper: &MyHelper,
     canister_id: &Principal,
     method: &str,
     args: &[u8],
     opt_func: &Option<(TypeEnv, Function)>,
-    offline: &Option<OfflineOutput>,
+    opts: &CallOpts,
+) -> anyhow::Result<IDLArgs> {
+    let is_query = opts.query.unwrap_or_else(|| {
+        opt_func
+            .as_ref()
+            .map(|(_, f)| f.is_query())
+            .unwrap_or(false)
+    });
+    let Some(bytes) =
+        send_call(helper, canister_id, method, args, is_query, opt_func, opts).await?
+    else {
+        return Ok(IDLArgs::new(&[]));
+    };
+    decode_reply(helper, &bytes, opt_func)
+}
+/// Decode the reply of a call, and remember the types of func values in the reply.
+fn decode_reply(
+    helper: &MyHelper,
+    bytes: &[u8],
+    opt_func: &Option<(TypeEnv, Function)>,
 ) -> anyhow::Result<IDLArgs> {
+    Ok(if let Some((env, func)) = opt_func {
+        let res = IDLArgs::from_bytes_with_types(bytes, env, &func.rets)?;
+        let mut func_types = helper.func_types.borrow_mut();
+        for (v, ty) in res.args.iter().zip(func.rets.iter()) {
+            crate::utils::collect_func_types(env, ty, v, &mut func_types);
+        }
+        res
+    } else {
+        IDLArgs::from_bytes(bytes)?
+    })
+}
+/// Send a blob to a canister without Candid encoding, and return the reply blob undecoded.
+async fn raw_call(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+    is_query: bool,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    send_call(
+        helper,
+        canister_id,
+        method,
+        args,
+        is_query,
+        &None,
+        &CallOpts::default(),
+    )
+    .await
+}
+/// Send encoded args to a canister and return the reply bytes. `opt_func` is only used for logging.
+/// In offline mode, the message is signed and saved, and None is returned.
+async fn send_call(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+    is_query: bool,
+    opt_func: &Option<(TypeEnv, Function)>,
+    opts: &CallOpts,
+) -> anyhow::Result<Option<Vec<u8>>> {
     use crate::offline::*;
-    let agent = &helper.agent;
-    let effective_id = get_effective_canister_id(*canister_id, method, args)?
-        .unwrap_or(helper.default_effective_canister_id);
-    let is_query = opt_func
-        .as_ref()
-        .map(|(_, f)| f.is_query())
-        .unwrap_or(false);
+    let offline = &helper.offline;
+    opts.check_target(helper, canister_id)?;
+    let agent = opts.agent(helper);
+    let effective_id = opts.effective_canister_id(helper, canister_id, method, args)?;
+    let mut entry = helper.call_log.as_ref().map(|log| {
+        let mode = if is_query { "query" } else { "update" };
+        log.entry(agent, *canister_id, method, mode, Some(effective_id))
+            .args(
+                args,
+                opt_func.as_ref().map(|(env, f)| (env, f.args.as_slice())),
+            )
+    });
     let bytes = if is_query {
         let mut builder = agent.query(canister_id, method);
         builder = builder
             .with_arg(args)
             .with_effective_canister_id(effective_id);
+        if let Some(expiry) = opts.ingress_expiry {
+            builder = builder.expire_after(expiry);
+        }
         if let Some(offline) = offline {
             let mut msgs = helper.messages.borrow_mut();
             let signed = builder.sign()?;
@@ -968,42 +1835,281 @@ async fn call(
             };
             msgs.push(message.clone());
             output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            log_signed(helper, entry);
+            return Ok(None);
         } else {
-            builder.call().await?
+            with_timeout(opts.timeout, async { Ok(builder.call().await?) }).await
         }
     } else {
         let mut builder = agent.update(canister_id, method);
         builder = builder
             .with_arg(args)
             .with_effective_canister_id(effective_id);
+        if let Some(expiry) = opts.ingress_expiry {
+            builder = builder.expire_after(expiry);
+        }
         if let Some(offline) = offline {
-            let mut msgs = helper.messages.borrow_mut();
             let signed = builder.sign()?;
-            let status = agent.sign_request_status(effective_id, signed.request_id)?;
-            let message = IngressWithStatus {
-                ingress: Ingress {
-                    call_type: "update".to_owned(),
-                    request_id: Some(hex::encode(signed.request_id.as_slice())),
-                    content: hex::encode(signed.signed_update),
-                },
-                request_status: Some(RequestStatus {
-                    canister_id: status.effective_canister_id,
-                    request_id: hex::encode(status.request_id.as_slice()),
-                    content: hex::encode(status.signed_request_status),
-                }),
-            };
-            msgs.push(message.clone());
-            output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            save_signed_update(helper, agent, effective_id, &signed, offline)?;
+            if let Some(entry) = &mut entry {
+                entry.set_request_id(&signed.request_id);
+            }
+            log_signed(helper, entry);
+            return Ok(None);
         } else {
-            builder.call_and_wait().await?
+            let (request_id, res) = update_and_wait(agent, builder, opts.timeout).await;
+            if let (Some(entry), Some(request_id)) = (&mut entry, request_id) {
+                entry.set_request_id(&request_id);
+            }
+            res
         }
     };
-    let res = if let Some((env, func)) = opt_func {
-        IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?
+    let rets = opt_func.as_ref().map(|(env, f)| (env, f.rets.as_slice()));
+    if let (Some(log), Some(entry)) = (&helper.call_log, entry) {
+        log.finish(entry, bytes.as_deref(), rets);
+    }
+    Ok(Some(bytes?))
+}
+/// Save a signed update call together with its signed request_status in offline mode.
+fn save_signed_update(
+    helper: &MyHelper,
+    agent: &Agent,
+    effective_id: Principal,
+    signed: &ic_transport_types::signed::SignedUpdate,
+    offline: &OfflineOutput,
+) -> anyhow::Result<()> {
+    use crate::offline::*;
+    let status = agent.sign_request_status(effective_id, signed.request_id)?;
+    let message = IngressWithStatus {
+        ingress: Ingress {
+            call_type: "update".to_owned(),
+            request_id: Some(hex::encode(signed.request_id.as_slice())),
+            content: hex::encode(&signed.signed_update),
+        },
+        request_status: Some(RequestStatus {
+            canister_id: status.effective_canister_id,
+            request_id: hex::encode(status.request_id.as_slice()),
+            content: hex::encode(status.signed_request_status),
+        }),
+    };
+    helper.messages.borrow_mut().push(message.clone());
+    output_message(serde_json::to_string(&message)?, offline)?;
+    Ok(())
+}
+fn log_signed(helper: &MyHelper, entry: Option<crate::call_log::LogEntry>) {
+    if let (Some(log), Some(entry)) = (&helper.call_log, entry) {
+        log.finish_signed(entry);
+    }
+}
+/// Sign and send an update call, so that the request id is known even if the call fails.
+pub async fn update_and_wait(
+    agent: &Agent,
+    builder: UpdateBuilder<'_>,
+    timeout: Option<Duration>,
+) -> (Option<RequestId>, anyhow::Result<Vec<u8>>) {
+    let effective_id = builder.effective_canister_id;
+    let signed = match builder.sign() {
+        Ok(signed) => signed,
+        Err(e) => return (None, Err(e.into())),
+    };
+    let request_id = signed.request_id;
+    let res = with_timeout(timeout, async {
+        match agent
+            .update_signed(effective_id, signed.signed_update)
+            .await?
+        {
+            CallResponse::Response(blob) => Ok(blob),
+            CallResponse::Poll(request_id) => Ok(agent.wait(&request_id, effective_id).await?.0),
+        }
+    })
+    .await;
+    (Some(request_id), res)
+}
+/// Fail with a timeout error if the future doesn't complete in time.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    f: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, f)
+            .await
+            .map_err(|_| crate::utils::CallTimeout(timeout))?,
+        None => f.await,
+    }
+}
+
+/// An update call sent by `submit`, represented as a record in the script.
+pub struct SubmittedRequest {
+    pub request_id: RequestId,
+    pub canister_id: Principal,
+    pub method_name: String,
+    pub effective_canister_id: Principal,
+    pub network: String,
+    pub sender: Principal,
+}
+impl SubmittedRequest {
+    pub fn to_value(&self) -> IDLValue {
+        crate::utils::record(vec![
+            (
+                "request_id",
+                IDLValue::Blob(self.request_id.as_slice().to_vec()),
+            ),
+            ("canister_id", IDLValue::Principal(self.canister_id)),
+            ("method_name", IDLValue::Text(self.method_name.clone())),
+            (
+                "effective_canister_id",
+                IDLValue::Principal(self.effective_canister_id),
+            ),
+            ("network", IDLValue::Text(self.network.clone())),
+            ("sender", IDLValue::Principal(self.sender)),
+        ])
+    }
+    pub fn from_fields(helper: &MyHelper, fs: &[IDLField]) -> Result<Self> {
+        let usage = || anyhow!("expect a record returned by submit");
+        let request_id = get_field(fs, "request_id")
+            .and_then(crate::utils::as_blob)
+            .and_then(|id| <[u8; 32]>::try_from(id).ok())
+            .ok_or_else(usage)?;
+        let (
+            Some(IDLValue::Principal(canister_id)),
+            Some(IDLValue::Text(method_name)),
+            Some(IDLValue::Principal(effective_canister_id)),
+        ) = (
+            get_field(fs, "canister_id"),
+            get_field(fs, "method_name"),
+            get_field(fs, "effective_canister_id"),
+        )
+        else {
+            return Err(usage());
+        };
+        Ok(SubmittedRequest {
+            request_id: RequestId::new(&request_id),
+            canister_id: *canister_id,
+            method_name: method_name.clone(),
+            effective_canister_id: *effective_canister_id,
+            network: match get_field(fs, "network") {
+                Some(IDLValue::Text(network)) => network.clone(),
+                _ => helper.current_network.clone(),
+            },
+            sender: match get_field(fs, "sender") {
+                Some(IDLValue::Principal(sender)) => *sender,
+                _ => helper.agent.get_principal().map_err(|e| anyhow!(e))?,
+            },
+        })
+    }
+    /// The agent that signed the request. Reading the status of a request needs the same sender.
+    fn agent(&self, helper: &MyHelper) -> Result<Agent> {
+        let mut agent = helper.agent.clone();
+        if agent.get_principal().ok() != Some(self.sender) {
+            let identity = helper
+                .find_identity(&IDLValue::Principal(self.sender))
+                .with_context(|| {
+                    format!(
+                        "request {} is sent by {}",
+                        hex::encode(self.request_id.as_slice()),
+                        self.sender
+                    )
+                })?;
+            agent.set_arc_identity(identity);
+        }
+        Ok(agent)
+    }
+}
+/// Send an update call without waiting for the reply. In offline mode, the message is signed and saved.
+async fn submit(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+    opt_func: &Option<(TypeEnv, Function)>,
+    opts: &CallOpts,
+) -> anyhow::Result<SubmittedRequest> {
+    opts.check_target(helper, canister_id)?;
+    let agent = opts.agent(helper);
+    let effective_id = opts.effective_canister_id(helper, canister_id, method, args)?;
+    let mut entry = helper.call_log.as_ref().map(|log| {
+        log.entry(agent, *canister_id, method, "update", Some(effective_id))
+            .args(
+                args,
+                opt_func.as_ref().map(|(env, f)| (env, f.args.as_slice())),
+            )
+    });
+    let mut builder = agent
+        .update(canister_id, method)
+        .with_arg(args)
+        .with_effective_canister_id(effective_id);
+    if let Some(expiry) = opts.ingress_expiry {
+        builder = builder.expire_after(expiry);
+    }
+    let signed = builder.sign()?;
+    if let Some(entry) = &mut entry {
+        entry.set_request_id(&signed.request_id);
+    }
+    if let Some(offline) = &helper.offline {
+        save_signed_update(helper, agent, effective_id, &signed, offline)?;
+        log_signed(helper, entry);
     } else {
-        IDLArgs::from_bytes(&bytes)?
+        let res = agent
+            .update_signed(effective_id, signed.signed_update.clone())
+            .await
+            .map_err(anyhow::Error::from);
+        if let (Some(log), Some(entry)) = (&helper.call_log, entry) {
+            match &res {
+                Ok(_) => log.finish_submitted(entry),
+                Err(e) => log.finish(entry, Err(e), None),
+            }
+        }
+        res?;
+    }
+    Ok(SubmittedRequest {
+        request_id: signed.request_id,
+        canister_id: *canister_id,
+        method_name: method.to_string(),
+        effective_canister_id: effective_id,
+        network: helper.current_network.clone(),
+        sender: agent.get_principal().map_err(|e| anyhow!(e))?,
+    })
+}
+/// Read the request_status of a submitted call, and decode the reply if available.
+async fn request_status(helper: &MyHelper, req: &SubmittedRequest) -> anyhow::Result<IDLValue> {
+    use crate::utils::{reject_to_value, variant};
+    use ic_agent::agent::RequestStatusResponse;
+    let (status, _) = req
+        .agent(helper)?
+        .request_status_raw(&req.request_id, req.effective_canister_id)
+        .await?;
+    Ok(match status {
+        RequestStatusResponse::Unknown => variant("Unknown", IDLValue::Null),
+        RequestStatusResponse::Received => variant("Received", IDLValue::Null),
+        RequestStatusResponse::Processing => variant("Processing", IDLValue::Null),
+        RequestStatusResponse::Replied(reply) => {
+            let signature = method_signature(helper, req.canister_id, &req.method_name).await?;
+            let res = decode_reply(helper, &reply.arg, &signature)?;
+            variant("Replied", args_to_value(res))
+        }
+        RequestStatusResponse::Rejected(reject) => variant("Rejected", reject_to_value(&reject)),
+        RequestStatusResponse::Done => variant("Done", IDLValue::Null),
+    })
+}
+/// Poll the request_status of a submitted call until it is replied or `timeout` milliseconds have passed.
+async fn wait_request(
+    helper: &MyHelper,
+    req: &SubmittedRequest,
+    timeout: u64,
+) -> anyhow::Result<IDLArgs> {
+    let res = tokio::time::timeout(
+        std::time::Duration::from_millis(timeout),
+        req.agent(helper)?
+            .wait(&req.request_id, req.effective_canister_id),
+    )
+    .await;
+    let Ok(res) = res else {
+        return Err(anyhow!(
+            "request {} is still pending after {timeout} ms",
+            hex::encode(req.request_id.as_slice())
+        ));
     };
-    Ok(res)
+    let (bytes, _) = res?;
+    let signature = method_signature(helper, req.canister_id, &req.method_name).await?;
+    decode_reply(helper, &bytes, &signature)
 }
@@ -7,6 +7,7 @@ use candid::{
     Decode, Encode, Principal, TypeEnv,
 };
 use candid_parser::{check_prog, configs::Configs, pretty_check_file, pretty_parse, IDLProg};
+use futures::future::LocalBoxFuture;
 use ic_agent::{Agent, Identity};
 use rustyline::completion::{extract_word, Completer, FilenameCompleter, Pair};
 use rustyline::error::ReadlineError;
@@ -18,11 +19,14 @@ use rustyline_derive::Helper;
 use std::borrow::Cow::{self, Borrowed, Owned};
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::rc::Rc;
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 
 #[derive(Default, Clone)]
 pub struct CanisterMap(pub BTreeMap<Principal, CanisterInfo>);
+/// Types of func values by canister id and method name
+pub type FuncTypes = BTreeMap<(Principal, String), (TypeEnv, Function)>;
 #[derive(Default, Clone)]
 pub struct IdentityMap(pub BTreeMap<String, Arc<dyn Identity>>);
 #[derive(Default, Clone)]
@@ -36,6 +40,28 @@ pub struct CanisterInfo {
     pub init: Option<Vec<Type>>,
     pub profiling: Option<BTreeMap<u16, String>>,
 }
+const MAINNET_CANISTERS: [(&str, &str, Option<&str>); 4] = [
+    (
+        "nns",
+        "rrkah-fqaaa-aaaaa-aaaaq-cai",
+        Some(include_str!("governance.did")),
+    ),
+    (
+        "ledger",
+        "ryjl3-tyaaa-aaaaa-aaaba-cai",
+        Some(include_str!("ledger.did")),
+    ),
+    ("registry", "rwlgt-iiaaa-aaaaa-aaaaa-cai", None),
+    ("cycles_ledger", "um5iw-rqaaa-aaaaq-qaaba-cai", None),
+];
+/// The connection to a replica, see `MyHelper::switch_network`.
+#[derive(Clone)]
+pub struct Network {
+    pub url: String,
+    pub agent: Agent,
+    pub default_effective_canister_id: Principal,
+    pub canister_map: CanisterMap,
+}
 #[derive(Clone)]
 pub enum OfflineOutput {
     Json,
@@ -45,14 +71,25 @@ pub enum OfflineOutput {
     AsciiNoUrl,
 }
 impl CanisterMap {
-    pub fn get(&mut self, agent: &Agent, id: &Principal) -> anyhow::Result<&CanisterInfo> {
+    pub fn get(&mut self, helper: &MyHelper, id: &Principal) -> anyhow::Result<&CanisterInfo> {
         if !self.0.contains_key(id) {
-            let info = fetch_actor(agent, *id)?;
+            let info = helper.block_on(fetch_actor(&helper.agent, *id))?;
             self.0.insert(*id, info);
         }
         Ok(self.0.get(id).unwrap())
     }
 }
+impl MyHelper {
+    /// Fetch the interface of `id` into `canister_map` unless it's already there. The map is not
+    /// borrowed while fetching, so that other evaluations in `par_map` can use it.
+    pub async fn fetch_canister_info(&self, id: &Principal) -> anyhow::Result<()> {
+        if !self.canister_map.borrow().0.contains_key(id) {
+            let info = fetch_actor(&self.agent, *id).await?;
+            self.canister_map.borrow_mut().0.insert(*id, info);
+        }
+        Ok(())
+    }
+}
 impl CanisterInfo {
     pub fn match_method(&self, meth: &str) -> Vec<Pair> {
         self.methods
@@ -80,9 +117,15 @@ pub struct MyHelper {
     hinter: HistoryHinter,
     pub colored_prompt: String,
     pub offline: Option<OfflineOutput>,
+    /// Root key from `--root-key` in offline mode, recorded in `messages.json`
+    pub root_key: Option<Vec<u8>>,
     pub canister_map: RefCell<CanisterMap>,
     pub identity_map: IdentityMap,
     pub current_identity: String,
+    /// Seed for deriving identities without a pem file, set by `--seed` or the `seed` command
+    pub seed: Option<String>,
+    /// HSM PINs entered in this session, by PKCS#11 library path and slot index
+    pub hsm_pins: BTreeMap<(String, usize), String>,
     pub agent_url: String,
     pub agent: Agent,
     pub config: Configs,
@@ -92,6 +135,21 @@ pub struct MyHelper {
     pub messages: RefCell<Vec<crate::offline::IngressWithStatus>>,
     pub verbose: bool,
     pub default_effective_canister_id: Principal,
+    pub call_log: Option<crate::call_log::CallLog>,
+    /// Shared async runtime for all network requests, so that connections can be reused
+    pub runtime: Arc<Runtime>,
+    /// Types of func values seen in typed replies, used to call func references. Shared by all
+    /// spawned helpers, so that types learned inside functions are kept
+    pub func_types: Rc<RefCell<FuncTypes>>,
+    /// Name of the active network, whose connection is stored in `agent`, `agent_url`,
+    /// `default_effective_canister_id` and `canister_map`
+    pub current_network: String,
+    /// Other networks, shared by all spawned helpers
+    pub networks: Rc<RefCell<BTreeMap<String, Network>>>,
+    /// Replica URLs of networks that are connected on first use, e.g., from dfx `networks.json`
+    pub network_urls: Rc<RefCell<BTreeMap<String, String>>>,
+    /// Interfaces imported for networks that are not connected yet, added on first use
+    pub network_canisters: Rc<RefCell<BTreeMap<String, CanisterMap>>>,
 }
 
 impl MyHelper {
@@ -106,15 +164,25 @@ impl MyHelper {
             canister_map: self.canister_map.clone(),
             identity_map: self.identity_map.clone(),
             current_identity: self.current_identity.clone(),
+            seed: self.seed.clone(),
+            hsm_pins: self.hsm_pins.clone(),
             env: self.env.clone(),
             func_env: self.func_env.clone(),
             base_path: self.base_path.clone(),
             agent: self.agent.clone(),
             agent_url: self.agent_url.clone(),
             offline: self.offline.clone(),
+            root_key: self.root_key.clone(),
             messages: self.messages.clone(),
             verbose: self.verbose,
             default_effective_canister_id: self.default_effective_canister_id,
+            call_log: self.call_log.clone(),
+            runtime: self.runtime.clone(),
+            func_types: self.func_types.clone(),
+            current_network: self.current_network.clone(),
+            networks: self.networks.clone(),
+            network_urls: self.network_urls.clone(),
+            network_canisters: self.network_canisters.clone(),
         }
     }
     pub fn new(
@@ -124,31 +192,11 @@ impl MyHelper {
         verbose: bool,
     ) -> Self {
         let runtime = Runtime::new().expect("Unable to create a runtime");
-        let default_effective_canister_id = runtime
-            .block_on(async {
-                use serde_with::base64::Base64;
-                #[serde_with::serde_as]
-                #[derive(serde::Deserialize)]
-                pub struct RawCanisterId {
-                    #[serde_as(as = "Base64")]
-                    pub canister_id: Vec<u8>,
-                }
-                #[derive(serde::Deserialize)]
-                struct Topology {
-                    pub default_effective_canister_id: RawCanisterId,
-                }
-                let resp = reqwest::get(format!("{}/_/topology", agent_url.trim_end_matches('/')))
-                    .await
-                    .ok()?;
-                if resp.status().is_success() {
-                    resp.json::<Topology>().await.ok().map(|topology| {
-                        Principal::from_slice(&topology.default_effective_canister_id.canister_id)
-                    })
-                } else {
-                    None
-                }
-            })
-            .unwrap_or(Principal::management_canister());
+        let default_effective_canister_id = if offline.is_some() {
+            Principal::management_canister()
+        } else {
+            runtime.block_on(fetch_default_effective_canister_id(&agent_url))
+        };
         let mut res = MyHelper {
             completer: FilenameCompleter::new(),
             highlighter: MatchingBracketHighlighter::new(),
@@ -158,6 +206,8 @@ impl MyHelper {
             canister_map: RefCell::new(CanisterMap::default()),
             identity_map: IdentityMap::default(),
             current_identity: "anonymous".to_owned(),
+            seed: None,
+            hsm_pins: BTreeMap::new(),
             config: "".parse::<Configs>().unwrap(),
             env: Env::default(),
             func_env: FuncEnv::default(),
@@ -166,17 +216,43 @@ impl MyHelper {
             agent,
             agent_url,
             offline,
+            root_key: None,
             verbose,
             default_effective_canister_id,
+            call_log: None,
+            runtime: Arc::new(runtime),
+            func_types: Rc::new(RefCell::new(BTreeMap::new())),
+            current_network: "default".to_owned(),
+            networks: Rc::new(RefCell::new(BTreeMap::new())),
+            network_urls: Rc::new(RefCell::new(BTreeMap::new())),
+            network_canisters: Rc::new(RefCell::new(BTreeMap::new())),
         };
         res.fetch_root_key_if_needed().unwrap();
         res.load_prelude().unwrap();
         res
     }
     fn is_mainnet(&self) -> bool {
-        self.agent_url == "https://icp0.io" || self.agent_url == "https://ic0.app"
+        is_mainnet(&self.agent_url)
+    }
+    /// Look up an identity by name or by principal.
+    pub fn find_identity(&self, v: &IDLValue) -> anyhow::Result<Arc<dyn Identity>> {
+        match v {
+            IDLValue::Text(name) => self.identity_map.0.get(name).cloned(),
+            IDLValue::Principal(id) => self
+                .identity_map
+                .0
+                .values()
+                .find(|identity| identity.sender().is_ok_and(|p| p == *id))
+                .cloned(),
+            _ => return Err(anyhow::anyhow!("expects an identity name or principal")),
+        }
+        .ok_or_else(|| {
+            anyhow::anyhow!("identity {v} not found, use the identity command to create it first")
+        })
     }
-    fn load_prelude(&mut self) -> anyhow::Result<()> {
+    /// Bind the well-known canisters of the active network, and unbind the mainnet canisters
+    /// when connected to another replica.
+    pub fn load_prelude(&mut self) -> anyhow::Result<()> {
         self.identity_map.0.insert(
             "anonymous".to_string(),
             Arc::new(ic_agent::identity::AnonymousIdentity),
@@ -186,30 +262,16 @@ impl MyHelper {
             Principal::from_text("aaaaa-aa")?,
             Some(include_str!("ic.did")),
         )?;
-        if self.is_mainnet() {
-            self.preload_canister(
-                "nns".to_string(),
-                Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai")?,
+        let is_mainnet = self.is_mainnet();
+        for (name, id, did_file) in MAINNET_CANISTERS {
+            let id = Principal::from_text(id)?;
+            if is_mainnet {
                 // only load did file in offline mode
-                self.offline
-                    .as_ref()
-                    .map(|_| include_str!("governance.did")),
-            )?;
-            self.preload_canister(
-                "ledger".to_string(),
-                Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")?,
-                self.offline.as_ref().map(|_| include_str!("ledger.did")),
-            )?;
-            self.preload_canister(
-                "registry".to_string(),
-                Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai")?,
-                None,
-            )?;
-            self.preload_canister(
-                "cycles_ledger".to_string(),
-                Principal::from_text("um5iw-rqaaa-aaaaq-qaaba-cai")?,
-                None,
-            )?;
+                let did_file = did_file.filter(|_| self.offline.is_some());
+                self.preload_canister(name.to_string(), id, did_file)?;
+            } else if self.env.0.get(name) == Some(&IDLValue::Principal(id)) {
+                self.env.0.remove(name);
+            }
         }
         Ok(())
     }
@@ -229,15 +291,154 @@ impl MyHelper {
         self.env.0.insert(name, IDLValue::Principal(id));
         Ok(())
     }
+    /// Connect to a replica with the current identity.
+    pub fn connect(&self, url: &str) -> anyhow::Result<Network> {
+        self.block_on(self.connect_async(url))
+    }
+    pub async fn connect_async(&self, url: &str) -> anyhow::Result<Network> {
+        if self.offline.is_some() {
+            return Err(anyhow::anyhow!(
+                "Messages in offline mode are only signed for {}, use --replica to choose the network",
+                self.agent_url
+            ));
+        }
+        let mut agent = create_agent(url)?;
+        if let Some(identity) = self.identity_map.0.get(&self.current_identity) {
+            agent.set_arc_identity(identity.clone());
+        }
+        if !is_mainnet(url) {
+            agent.fetch_root_key().await?;
+        }
+        let default_effective_canister_id = fetch_default_effective_canister_id(url).await;
+        let mut canister_map = CanisterMap::default();
+        canister_map.0.insert(
+            Principal::management_canister(),
+            did_to_canister_info("ic", FileSource::Text(include_str!("ic.did")), None)?,
+        );
+        Ok(Network {
+            url: url.to_string(),
+            agent,
+            default_effective_canister_id,
+            canister_map,
+        })
+    }
+    /// Get a defined network, or connect to `local` or `ic` on first use.
+    fn network(&self, name: &str) -> anyhow::Result<Network> {
+        self.block_on(self.network_async(name))
+    }
+    async fn network_async(&self, name: &str) -> anyhow::Result<Network> {
+        if let Some(network) = self.networks.borrow().get(name) {
+            return Ok(network.clone());
+        }
+        let url = self
+            .network_urls
+            .borrow()
+            .get(name)
+            .cloned()
+            .or_else(|| known_network_url(name).map(String::from))
+            .ok_or_else(|| {
+                anyhow::anyhow!("Unknown network {name}, define it with network {name} = \"<url>\"")
+            })?;
+        let mut network = self.connect_async(&url).await?;
+        self.add_pending_canisters(name, &mut network);
+        self.networks
+            .borrow_mut()
+            .insert(name.to_string(), network.clone());
+        Ok(network)
+    }
+    fn add_pending_canisters(&self, name: &str, network: &mut Network) {
+        if let Some(map) = self.network_canisters.borrow_mut().remove(name) {
+            network.canister_map.0.extend(map.0);
+        }
+    }
+    /// Add canister interfaces for the network `name`. If the network is not connected yet, they
+    /// are kept until it is, so that importing a project doesn't need the replica to be running.
+    pub fn add_canisters(&self, name: &str, infos: Vec<(Principal, CanisterInfo)>) {
+        if name == self.current_network {
+            self.canister_map.borrow_mut().0.extend(infos);
+        } else if let Some(network) = self.networks.borrow_mut().get_mut(name) {
+            network.canister_map.0.extend(infos);
+        } else {
+            self.network_canisters
+                .borrow_mut()
+                .entry(name.to_string())
+                .or_default()
+                .0
+                .extend(infos);
+        }
+    }
+    fn load_network(&mut self, name: &str, network: Network) {
+        self.current_network = name.to_string();
+        self.agent_url = network.url;
+        self.agent = network.agent;
+        if let Some(identity) = self.identity_map.0.get(&self.current_identity) {
+            self.agent.set_arc_identity(identity.clone());
+        }
+        self.default_effective_canister_id = network.default_effective_canister_id;
+        self.canister_map = RefCell::new(network.canister_map);
+    }
+    /// Make `name` the active network. If `network` is provided, it replaces the previous definition.
+    pub fn switch_network(&mut self, name: &str, network: Option<Network>) -> anyhow::Result<()> {
+        let network = match network {
+            Some(mut network) => {
+                self.add_pending_canisters(name, &mut network);
+                self.networks
+                    .borrow_mut()
+                    .insert(name.to_string(), network.clone());
+                network
+            }
+            None if name == self.current_network => return Ok(()),
+            None => self.network(name)?,
+        };
+        if name != self.current_network {
+            let current = Network {
+                url: self.agent_url.clone(),
+                agent: self.agent.clone(),
+                default_effective_canister_id: self.default_effective_canister_id,
+                canister_map: self.canister_map.take(),
+            };
+            self.networks
+                .borrow_mut()
+                .insert(self.current_network.clone(), current);
+        }
+        self.load_network(name, network);
+        Ok(())
+    }
+    /// Run `f` on the network `name` without changing the active network.
+    pub async fn on_network<T>(
+        &self,
+        name: &str,
+        f: impl for<'a> FnOnce(&'a MyHelper) -> LocalBoxFuture<'a, anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        if name == self.current_network {
+            return f(self).await;
+        }
+        let mut helper = self.spawn();
+        helper.load_network(name, self.network_async(name).await?);
+        let res = f(&helper).await;
+        // keep the interfaces fetched from this network
+        if let Some(network) = self.networks.borrow_mut().get_mut(name) {
+            network.canister_map = helper.canister_map.into_inner();
+        }
+        res
+    }
     pub fn fetch_root_key_if_needed(&mut self) -> anyhow::Result<()> {
         if self.offline.is_none() && !self.is_mainnet() {
-            let runtime = Runtime::new().expect("Unable to create a runtime");
-            runtime.block_on(self.agent.fetch_root_key())?;
+            self.block_on(self.agent.fetch_root_key())?;
         };
         Ok(())
     }
+    /// Run a future to completion on the shared runtime.
+    pub fn block_on<F: std::future::Future>(&self, f: F) -> F::Output {
+        block_on(&self.runtime, f)
+    }
     pub fn dump_ingress(&self) -> anyhow::Result<()> {
-        crate::offline::dump_ingress(&self.messages.borrow())
+        let network = crate::offline::OfflineNetwork {
+            name: self.current_network.clone(),
+            url: self.agent_url.clone(),
+            root_key: self.root_key.as_ref().map(hex::encode),
+        };
+        crate::offline::dump_ingress(&self.messages.borrow(), network)
     }
 }
 
@@ -249,12 +450,12 @@ enum Partial {
 impl Partial {
     fn get_func_type<'a>(
         &'a self,
-        agent: &'a Agent,
+        helper: &MyHelper,
         map: &'a mut CanisterMap,
     ) -> Option<(&'a TypeEnv, &'a [Type])> {
         match self {
             Partial::Call(canister_id, method) => {
-                let info = map.get(agent, canister_id).ok()?;
+                let info = map.get(helper, canister_id).ok()?;
                 let func = info.methods.get(method)?;
                 Some((&info.env, &func.args))
             }
@@ -392,7 +593,7 @@ impl Completer for MyHelper {
         match partial_parse(line, pos, self) {
             Some((pos, Partial::Call(canister_id, meth))) => {
                 let mut map = self.canister_map.borrow_mut();
-                Ok(match map.get(&self.agent, &canister_id) {
+                Ok(match map.get(self, &canister_id) {
                     Ok(info) => (pos, info.match_method(&meth)),
                     Err(_) => (pos, Vec::new()),
                 })
@@ -410,7 +611,7 @@ fn match_type(line: &str, helper: &MyHelper) -> Option<(usize, Vec<Pair>)> {
     use std::collections::HashSet;
     let (pos, arg_idx, call) = find_lastest_call(line, helper)?;
     let mut map = helper.canister_map.borrow_mut();
-    let (env, args) = call.get_func_type(&helper.agent, &mut map)?;
+    let (env, args) = call.get_func_type(helper, &mut map)?;
     let expect_ty = &args[arg_idx];
     let mut res = Vec::new();
     let mut gamma = HashSet::new();
@@ -438,7 +639,7 @@ fn find_lastest_call(line: &str, helper: &MyHelper) -> Option<(usize, usize, Par
     let given_args = line[arg_pos..].matches(',').count();
     let (_, call) = partial_parse(line, arg_pos, helper)?;
     let mut map = helper.canister_map.borrow_mut();
-    let (_, args) = call.get_func_type(&helper.agent, &mut map)?;
+    let (_, args) = call.get_func_type(helper, &mut map)?;
     if given_args >= args.len() {
         return None;
     }
@@ -449,7 +650,7 @@ fn hint_method(line: &str, pos: usize, helper: &MyHelper) -> Option<String> {
     use candid_parser::configs::{Scope, ScopePos};
     let (_, given_args, call) = find_lastest_call(line, helper)?;
     let mut map = helper.canister_map.borrow_mut();
-    let (env, args) = call.get_func_type(&helper.agent, &mut map)?;
+    let (env, args) = call.get_func_type(helper, &mut map)?;
     let method = match &call {
         Partial::Call(_, method) => Some(method),
         _ => None,
@@ -527,7 +728,69 @@ impl Validator for MyHelper {
     }
 }
 
-#[tokio::main]
+/// The network name and URL for the `--replica` option and the `replica` command.
+pub fn replica_network(replica: &str) -> (&str, &str) {
+    match known_network_url(replica) {
+        Some(url) => (replica, url),
+        None => ("default", replica),
+    }
+}
+/// The replica URL of the predefined network names.
+pub fn known_network_url(name: &str) -> Option<&'static str> {
+    match name {
+        "local" => Some("http://localhost:4943/"),
+        "ic" => Some("https://icp0.io"),
+        _ => None,
+    }
+}
+pub fn is_mainnet(url: &str) -> bool {
+    url == "https://icp0.io" || url == "https://ic0.app"
+}
+/// Run a future to completion on the runtime. When called from synchronous code inside an async
+/// evaluation, e.g., a builtin in a `par_map` worker, the evaluation is blocked in place until the
+/// future is ready.
+pub fn block_on<F: std::future::Future>(runtime: &Runtime, f: F) -> F::Output {
+    if tokio::runtime::Handle::try_current().is_ok() {
+        tokio::task::block_in_place(|| runtime.block_on(f))
+    } else {
+        runtime.block_on(f)
+    }
+}
+pub fn create_agent(url: &str) -> anyhow::Result<Agent> {
+    Ok(Agent::builder()
+        .with_url(url)
+        .with_max_tcp_error_retries(2)
+        .with_max_polling_time(std::time::Duration::from_secs(60 * 10))
+        .build()?)
+}
+async fn fetch_default_effective_canister_id(url: &str) -> Principal {
+    use serde_with::base64::Base64;
+    #[serde_with::serde_as]
+    #[derive(serde::Deserialize)]
+    pub struct RawCanisterId {
+        #[serde_as(as = "Base64")]
+        pub canister_id: Vec<u8>,
+    }
+    #[derive(serde::Deserialize)]
+    struct Topology {
+        pub default_effective_canister_id: RawCanisterId,
+    }
+    async {
+        let resp = reqwest::get(format!("{}/_/topology", url.trim_end_matches('/')))
+            .await
+            .ok()?;
+        if resp.status().is_success() {
+            resp.json::<Topology>().await.ok().map(|topology| {
+                Principal::from_slice(&topology.default_effective_canister_id.canister_id)
+            })
+        } else {
+            None
+        }
+    }
+    .await
+    .unwrap_or(Principal::management_canister())
+}
+
 async fn fetch_actor(agent: &Agent, canister_id: Principal) -> anyhow::Result<CanisterInfo> {
     let response = fetch_metadata(agent, canister_id, "metadata/candid:service").await;
     let profiling = fetch_metadata(agent, canister_id, "metadata/name")
@@ -728,3 +991,31 @@ fn test_partial_parse() -> anyhow::Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn test_add_canisters() -> anyhow::Result<()> {
+    let url = "https://icp0.io".to_string();
+    let agent = Agent::builder().with_url(url.clone()).build()?;
+    let mut helper = MyHelper::new(agent.clone(), url, Some(OfflineOutput::Json), false);
+    let id = Principal::from_text("bkyz2-fmaaa-aaaaa-qaaaq-cai")?;
+    let info = CanisterInfo {
+        env: TypeEnv::new(),
+        methods: BTreeMap::new(),
+        init: None,
+        profiling: None,
+    };
+    // importing for a network that is not connected doesn't connect to it
+    helper.add_canisters("local", vec![(id, info)]);
+    assert!(helper.networks.borrow().get("local").is_none());
+    assert!(!helper.canister_map.borrow().0.contains_key(&id));
+    let network = Network {
+        url: "http://127.0.0.1:4943".to_string(),
+        agent,
+        default_effective_canister_id: id,
+        canister_map: CanisterMap::default(),
+    };
+    helper.switch_network("local", Some(network))?;
+    assert!(helper.canister_map.borrow().0.contains_key(&id));
+    assert!(helper.network_canisters.borrow().is_empty());
+    Ok(())
+}
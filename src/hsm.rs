@@ -0,0 +1,234 @@
+//! Hardware security modules through a PKCS#11 library.
+use crate::utils::record;
+use anyhow::{anyhow, Result};
+use candid::types::value::IDLValue;
+use pkcs11::types::{
+    CKA_CLASS, CKA_ID, CKA_LABEL, CKF_SERIAL_SESSION, CKO_PUBLIC_KEY, CKR_ATTRIBUTE_SENSITIVE,
+    CKR_ATTRIBUTE_TYPE_INVALID, CKR_CRYPTOKI_ALREADY_INITIALIZED, CKR_OK, CK_ATTRIBUTE,
+    CK_ATTRIBUTE_TYPE, CK_FUNCTION_LIST, CK_FUNCTION_LIST_PTR, CK_FUNCTION_LIST_PTR_PTR,
+    CK_OBJECT_CLASS, CK_OBJECT_HANDLE, CK_RV, CK_SESSION_HANDLE, CK_SLOT_ID, CK_SLOT_INFO,
+    CK_TOKEN_INFO, CK_TRUE, CK_ULONG, CK_UNAVAILABLE_INFORMATION, CK_VOID_PTR,
+};
+use std::ptr;
+
+#[cfg(target_os = "macos")]
+const PKCS11_LIBPATH: &str = "/Library/OpenSC/lib/pkcs11/opensc-pkcs11.so";
+#[cfg(target_os = "linux")]
+const PKCS11_LIBPATH: &str = "/usr/lib/x86_64-linux-gnu/opensc-pkcs11.so";
+#[cfg(target_os = "windows")]
+const PKCS11_LIBPATH: &str = "C:/Program Files/OpenSC Project/OpenSC/pkcs11/opensc-pkcs11.dll";
+
+/// `PKCS11_LIBPATH` environment variable, or the default OpenSC path of the platform.
+pub fn default_lib_path() -> String {
+    std::env::var("PKCS11_LIBPATH").unwrap_or_else(|_| PKCS11_LIBPATH.to_string())
+}
+
+/// List the slots with a token present, in the order of `slot_index`, with the ids and labels of their public keys.
+pub fn list_slots(lib_path: &str) -> Result<IDLValue> {
+    let module = Module::load(lib_path)
+        .map_err(|e| anyhow!("Cannot load PKCS#11 library {lib_path}: {e}"))?;
+    let mut slots = Vec::new();
+    for (index, slot_id) in module.slot_list()?.into_iter().enumerate() {
+        let slot = module.slot_info(slot_id)?;
+        let token = module.token_info(slot_id)?;
+        let session = module.open_session(slot_id)?;
+        let keys = list_public_keys(&module, session);
+        module.close_session(session)?;
+        let keys = keys?
+            .into_iter()
+            .map(|(id, label)| {
+                record(vec![
+                    ("key_id", IDLValue::Text(hex::encode(id))),
+                    ("label", IDLValue::Text(label)),
+                ])
+            })
+            .collect();
+        #[allow(clippy::useless_conversion)] // CK_ULONG is 32-bit on Windows
+        let slot_id = u64::from(slot_id);
+        let text = |s: String| IDLValue::Text(s.trim().to_string());
+        slots.push(record(vec![
+            ("slot_index", IDLValue::Nat64(index as u64)),
+            ("slot_id", IDLValue::Nat64(slot_id)),
+            ("description", text(String::from(slot.slotDescription))),
+            (
+                "token",
+                record(vec![
+                    ("label", text(String::from(token.label))),
+                    ("manufacturer", text(String::from(token.manufacturerID))),
+                    ("model", text(String::from(token.model))),
+                    ("serial", text(String::from(token.serialNumber))),
+                ]),
+            ),
+            ("keys", IDLValue::Vec(keys)),
+        ]));
+    }
+    Ok(IDLValue::Vec(slots))
+}
+
+fn list_public_keys(module: &Module, session: CK_SESSION_HANDLE) -> Result<Vec<(Vec<u8>, String)>> {
+    let mut keys = Vec::new();
+    for object in module.find_public_keys(session)? {
+        let id = module.get_attribute(session, object, CKA_ID)?;
+        let label = module.get_attribute(session, object, CKA_LABEL)?;
+        keys.push((id, String::from_utf8_lossy(&label).to_string()));
+    }
+    Ok(keys)
+}
+
+/// A PKCS#11 library called through its function list. `pkcs11::Ctx` cannot be used here:
+/// a loaded `HardwareIdentity` keeps the library initialized, so `C_Initialize` returns
+/// `CKR_CRYPTOKI_ALREADY_INITIALIZED`, and `C_Finalize` would log out the identity.
+/// The library is only finalized if it was initialized by this module.
+struct Module {
+    _lib: libloading::Library,
+    functions: CK_FUNCTION_LIST_PTR,
+    finalize: bool,
+}
+
+fn check(rv: CK_RV) -> Result<()> {
+    match rv {
+        CKR_OK => Ok(()),
+        rv => Err(pkcs11::errors::Error::Pkcs11(rv).into()),
+    }
+}
+
+fn function<F>(f: Option<F>) -> Result<F> {
+    f.ok_or_else(|| anyhow!("Missing function in the PKCS#11 function list"))
+}
+
+impl Module {
+    fn load(lib_path: &str) -> Result<Self> {
+        let mut functions: CK_FUNCTION_LIST_PTR = ptr::null_mut();
+        // Loading runs the initializers of the library, which is trusted like the HSM identity
+        let lib = unsafe { libloading::Library::new(lib_path)? };
+        unsafe {
+            let get_function_list: libloading::Symbol<
+                unsafe extern "C" fn(CK_FUNCTION_LIST_PTR_PTR) -> CK_RV,
+            > = lib.get(b"C_GetFunctionList")?;
+            check(get_function_list(&mut functions))?;
+        }
+        if functions.is_null() {
+            return Err(anyhow!("C_GetFunctionList returns no function list"));
+        }
+        let mut module = Module {
+            _lib: lib,
+            functions,
+            finalize: false,
+        };
+        match function(module.functions().C_Initialize)?(ptr::null_mut()) {
+            CKR_OK => module.finalize = true,
+            CKR_CRYPTOKI_ALREADY_INITIALIZED => (),
+            rv => check(rv)?,
+        }
+        Ok(module)
+    }
+    fn functions(&self) -> &CK_FUNCTION_LIST {
+        // The function list is owned by the library, which lives as long as the module
+        unsafe { &*self.functions }
+    }
+    fn slot_list(&self) -> Result<Vec<CK_SLOT_ID>> {
+        let get_slot_list = function(self.functions().C_GetSlotList)?;
+        let mut count: CK_ULONG = 0;
+        check(get_slot_list(CK_TRUE, ptr::null_mut(), &mut count))?;
+        let mut slots: Vec<CK_SLOT_ID> = vec![0; count as usize];
+        check(get_slot_list(CK_TRUE, slots.as_mut_ptr(), &mut count))?;
+        slots.truncate(count as usize);
+        Ok(slots)
+    }
+    fn slot_info(&self, slot_id: CK_SLOT_ID) -> Result<CK_SLOT_INFO> {
+        let mut info = CK_SLOT_INFO::default();
+        check(function(self.functions().C_GetSlotInfo)?(
+            slot_id, &mut info,
+        ))?;
+        Ok(info)
+    }
+    fn token_info(&self, slot_id: CK_SLOT_ID) -> Result<CK_TOKEN_INFO> {
+        let mut info = CK_TOKEN_INFO::default();
+        check(function(self.functions().C_GetTokenInfo)?(
+            slot_id, &mut info,
+        ))?;
+        Ok(info)
+    }
+    fn open_session(&self, slot_id: CK_SLOT_ID) -> Result<CK_SESSION_HANDLE> {
+        let mut session: CK_SESSION_HANDLE = 0;
+        check(function(self.functions().C_OpenSession)?(
+            slot_id,
+            CKF_SERIAL_SESSION,
+            ptr::null_mut(),
+            None,
+            &mut session,
+        ))?;
+        Ok(session)
+    }
+    fn close_session(&self, session: CK_SESSION_HANDLE) -> Result<()> {
+        check(function(self.functions().C_CloseSession)?(session))
+    }
+    fn find_public_keys(&self, session: CK_SESSION_HANDLE) -> Result<Vec<CK_OBJECT_HANDLE>> {
+        let functions = self.functions();
+        let mut class = CKO_PUBLIC_KEY;
+        let mut template = [CK_ATTRIBUTE::new(CKA_CLASS)];
+        template[0].pValue = &mut class as *mut CK_OBJECT_CLASS as CK_VOID_PTR;
+        template[0].ulValueLen = std::mem::size_of::<CK_OBJECT_CLASS>() as CK_ULONG;
+        check(function(functions.C_FindObjectsInit)?(
+            session,
+            template.as_mut_ptr(),
+            template.len() as CK_ULONG,
+        ))?;
+        let find_objects = function(functions.C_FindObjects)?;
+        let mut objects = Vec::new();
+        let res = loop {
+            let mut batch: [CK_OBJECT_HANDLE; 16] = [0; 16];
+            let mut count: CK_ULONG = 0;
+            if let Err(e) = check(find_objects(
+                session,
+                batch.as_mut_ptr(),
+                batch.len() as CK_ULONG,
+                &mut count,
+            )) {
+                break Err(e);
+            }
+            if count == 0 {
+                break Ok(());
+            }
+            objects.extend_from_slice(&batch[..count as usize]);
+        };
+        check(function(functions.C_FindObjectsFinal)?(session))?;
+        res.map(|_| objects)
+    }
+    fn get_attribute(
+        &self,
+        session: CK_SESSION_HANDLE,
+        object: CK_OBJECT_HANDLE,
+        attribute: CK_ATTRIBUTE_TYPE,
+    ) -> Result<Vec<u8>> {
+        let get_attribute_value = function(self.functions().C_GetAttributeValue)?;
+        let mut template = [CK_ATTRIBUTE::new(attribute)];
+        let rv = get_attribute_value(session, object, template.as_mut_ptr(), 1);
+        if template[0].ulValueLen == CK_UNAVAILABLE_INFORMATION
+            || matches!(rv, CKR_ATTRIBUTE_SENSITIVE | CKR_ATTRIBUTE_TYPE_INVALID)
+        {
+            return Ok(Vec::new());
+        }
+        check(rv)?;
+        let mut value = vec![0u8; template[0].ulValueLen as usize];
+        template[0].pValue = value.as_mut_ptr() as CK_VOID_PTR;
+        check(get_attribute_value(
+            session,
+            object,
+            template.as_mut_ptr(),
+            1,
+        ))?;
+        value.truncate(template[0].ulValueLen as usize);
+        Ok(value)
+    }
+}
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        if self.finalize {
+            if let Ok(finalize) = function(self.functions().C_Finalize) {
+                finalize(ptr::null_mut());
+            }
+        }
+    }
+}
@@ -1,14 +1,18 @@
 use clap::Parser;
-use ic_agent::Agent;
 use rustyline::error::ReadlineError;
 use rustyline::CompletionType;
 
 mod account_identifier;
+mod call_log;
 mod command;
+mod dfx;
 mod error;
 mod exp;
 mod grammar;
 mod helper;
+mod hsm;
+mod identity;
+mod management;
 mod offline;
 mod profiling;
 mod selector;
@@ -30,9 +34,22 @@ where
 }
 
 fn repl(opts: Opts) -> anyhow::Result<()> {
-    let mut replica = opts.replica.unwrap_or_else(|| "local".to_string());
+    use crate::offline::Messages;
+    let send = match &opts.send {
+        Some(file) => {
+            let json = std::fs::read_to_string(file)?;
+            Some(serde_json::from_str::<Messages>(&json)?)
+        }
+        None => None,
+    };
+    let replica = opts.replica.unwrap_or_else(|| {
+        match send.as_ref().and_then(|msgs| msgs.network.as_ref()) {
+            Some(network) => network.url.clone(),
+            None if opts.offline => "ic".to_string(),
+            None => "local".to_string(),
+        }
+    });
     let offline = if opts.offline {
-        replica = "ic".to_string();
         let send_url = opts
             .url
             .unwrap_or_else(|| "https://qhmh2-niaaa-aaaab-qadta-cai.raw.icp0.io/?msg=".to_string());
@@ -47,29 +64,36 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
     } else {
         None
     };
-    let url = match replica.as_str() {
-        "local" => "http://localhost:4943/",
-        "ic" => "https://icp0.io",
-        url => url,
+    let (network, url) = helper::replica_network(&replica);
+    if opts.offline {
+        println!("Offline mode, signing messages for {url}");
+    } else {
+        println!("Ping {url}...");
+    }
+    let agent = helper::create_agent(url)?;
+    let root_key = match opts.root_key {
+        Some(file) => {
+            let root_key = read_root_key(&file)?;
+            agent.set_root_key(root_key.clone());
+            Some(root_key)
+        }
+        None => None,
     };
-    println!("Ping {url}...");
-    let agent = Agent::builder()
-        .with_url(url)
-        .with_max_tcp_error_retries(2)
-        .with_max_polling_time(std::time::Duration::from_secs(60 * 10))
-        .build()?;
 
     println!("Canister REPL");
     let config = rustyline::Config::builder()
         .history_ignore_space(true)
         .completion_type(CompletionType::List)
         .build();
-    let h = MyHelper::new(agent, url.to_string(), offline, opts.verbose);
-    if let Some(file) = opts.send {
-        use crate::offline::{send_messages, Messages};
-        let json = std::fs::read_to_string(file)?;
-        let msgs = serde_json::from_str::<Messages>(&json)?;
-        send_messages(&h, &msgs)?;
+    let mut h = MyHelper::new(agent, url.to_string(), offline, opts.verbose);
+    h.current_network = network.to_string();
+    h.seed = opts.seed;
+    h.root_key = root_key;
+    if let Some(file) = opts.call_log {
+        h.call_log = Some(call_log::CallLog::open(std::path::Path::new(&file))?);
+    }
+    if let Some(msgs) = send {
+        offline::send_messages(&h, &msgs)?;
         return Ok(());
     }
     let mut rl = rustyline::Editor::with_config(config)?;
@@ -98,8 +122,10 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
         rl.helper_mut().unwrap().verbose = true;
         let mut count = 1;
         loop {
-            let identity = &rl.helper().unwrap().current_identity;
-            let p = format!("{identity}@{replica} {count}> ");
+            let helper = rl.helper().unwrap();
+            let identity = &helper.current_identity;
+            let network = &helper.current_network;
+            let p = format!("{identity}@{network} {count}> ");
             rl.helper_mut().unwrap().colored_prompt =
                 format!("{}", console::style(&p).green().bold());
             let input = rl.readline(&p);
@@ -130,15 +156,32 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Read a DER encoded root key, either as raw bytes or as hex text.
+fn read_root_key(file: &str) -> anyhow::Result<Vec<u8>> {
+    let bytes = std::fs::read(file)?;
+    let root_key = match std::str::from_utf8(&bytes) {
+        Ok(text) => hex::decode(text.trim())
+            .map_err(|e| anyhow::anyhow!("Cannot decode root key {file} as hex: {e}"))?,
+        Err(_) => bytes,
+    };
+    if root_key.first() != Some(&0x30) {
+        return Err(anyhow::anyhow!("Root key {file} is not DER encoded"));
+    }
+    Ok(root_key)
+}
+
 #[derive(Parser)]
 #[clap(version, author)]
 struct Opts {
     #[clap(short, long)]
     /// Specifies replica URL, possible values: local, ic, URL
     replica: Option<String>,
-    #[clap(short, long, conflicts_with("replica"))]
-    /// Offline mode to be run in air-gap machines. All signed messages will be stored in messages.json
+    #[clap(short, long)]
+    /// Offline mode to be run in air-gap machines. All signed messages will be stored in messages.json, as an object with the network and the messages (previously a plain array). Signs for mainnet unless --replica is given
     offline: bool,
+    #[clap(long, requires("offline"))]
+    /// Root key of the replica in offline mode, as a DER file or hex text. Recorded in messages.json and checked when sending
+    root_key: Option<String>,
     #[clap(short, long, requires("offline"), value_parser = ["ascii", "json", "png", "ascii_no_url", "png_no_url"])]
     /// Offline output format
     format: Option<String>,
@@ -154,11 +197,17 @@ struct Opts {
     /// Enter repl once the script is finished
     interactive: bool,
     #[clap(short, long, conflicts_with("script"), conflicts_with("offline"))]
-    /// Send signed messages
+    /// Send signed messages. Defaults to the replica recorded in the messages file. Plain arrays of messages written by older versions are also accepted
     send: Option<String>,
     #[clap(short, long)]
     /// Run script in verbose mode. Non-verbose mode will only output text values.
     verbose: bool,
+    #[clap(long)]
+    /// Derive identities without a pem file from the seed and the identity name, instead of generating random keys
+    seed: Option<String>,
+    #[clap(long)]
+    /// Append a JSON line for each canister interaction to the file
+    call_log: Option<String>,
     #[clap(last = true)]
     /// Extra arguments passed to __main function when running a script
     extra_args: Vec<String>,
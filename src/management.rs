@@ -0,0 +1,860 @@
+use crate::exp::{CallMode, Exp, Method};
+use crate::helper::MyHelper;
+use crate::utils::{as_blob, fetch_metadata, get_field, gzip, record, variant};
+use anyhow::{anyhow, Result};
+use candid::{
+    types::value::{IDLArgs, IDLValue},
+    Principal,
+};
+use ic_agent::Agent;
+use sha2::{Digest, Sha256};
+
+/// Install code in one message if the module fits under this size, otherwise use the chunk store.
+const MAX_INSTALL_SIZE: usize = 2_000_000;
+const CHUNK_SIZE: usize = 1 << 20;
+const CYCLES_LEDGER: &str = "um5iw-rqaaa-aaaaq-qaaba-cai";
+
+/// Call a method of the management canister with a single argument.
+/// This goes through `call`, so that offline mode and the call log apply.
+pub fn call_ic(helper: &MyHelper, method: &str, arg: IDLValue) -> Result<IDLValue> {
+    call_canister(
+        helper,
+        Principal::management_canister(),
+        method,
+        arg,
+        Vec::new(),
+    )
+}
+
+/// Call a canister method with a single argument and call options, see `call_ic`.
+fn call_canister(
+    helper: &MyHelper,
+    canister_id: Principal,
+    method: &str,
+    arg: IDLValue,
+    opts: Vec<(String, Exp)>,
+) -> Result<IDLValue> {
+    let mut helper = helper.spawn();
+    helper.env.0.insert("__arg".to_string(), arg);
+    let exp = Exp::Call {
+        method: Some(Method {
+            canister: canister_id.to_text(),
+            method: method.to_string(),
+        }),
+        args: Some(vec![Exp::Path("__arg".to_string(), Vec::new())]),
+        mode: CallMode::Call,
+        opts,
+    };
+    exp.eval(&helper)
+}
+
+/// Install a Wasm module, uploading it to the chunk store first if it's too large for a single message.
+/// Upgrades are rejected if the new module breaks the installed Candid interface, unless `check` is false.
+/// Returns the module hash.
+pub fn install(
+    helper: &MyHelper,
+    canister_id: Principal,
+    wasm: &[u8],
+    arg: &[u8],
+    mode: IDLValue,
+    check: bool,
+) -> Result<Vec<u8>> {
+    if check && is_upgrade(&mode) {
+        if helper.offline.is_some() {
+            eprintln!("Warning: skip interface check in offline mode.");
+        } else {
+            assert_upgrade(helper, canister_id, wasm)?;
+        }
+    }
+    // The init arg is sent inline even when the module is uploaded in chunks.
+    if arg.len() > MAX_INSTALL_SIZE {
+        return Err(anyhow!(
+            "init arg is {} bytes, larger than the {MAX_INSTALL_SIZE} bytes limit of install_code",
+            arg.len()
+        ));
+    }
+    let module_hash = Sha256::digest(wasm).to_vec();
+    if wasm.len() + arg.len() <= MAX_INSTALL_SIZE {
+        // The interface is already checked above.
+        call_canister(
+            helper,
+            Principal::management_canister(),
+            "install_code",
+            record(vec![
+                ("mode", mode),
+                ("canister_id", IDLValue::Principal(canister_id)),
+                ("wasm_module", IDLValue::Blob(wasm.to_vec())),
+                ("arg", IDLValue::Blob(arg.to_vec())),
+                ("sender_canister_version", IDLValue::None),
+            ]),
+            vec![("skip_check".to_string(), Exp::Bool(true))],
+        )?;
+        return Ok(module_hash);
+    }
+    let canister = || ("canister_id", IDLValue::Principal(canister_id));
+    // The chunk store cannot be queried in offline mode, so every chunk is uploaded.
+    let stored: Vec<Vec<u8>> = if helper.offline.is_some() {
+        Vec::new()
+    } else {
+        match call_ic(helper, "stored_chunks", record(vec![canister()]))? {
+            IDLValue::Vec(hashes) => hashes.into_iter().filter_map(chunk_hash).collect(),
+            _ => return Err(anyhow!("unexpected result from stored_chunks")),
+        }
+    };
+    let chunks: Vec<_> = wasm.chunks(CHUNK_SIZE).collect();
+    let mut hashes = Vec::with_capacity(chunks.len());
+    for (i, chunk) in chunks.iter().enumerate() {
+        let hash = Sha256::digest(chunk).to_vec();
+        if stored.contains(&hash) {
+            if helper.verbose {
+                eprintln!("Chunk {}/{} already uploaded", i + 1, chunks.len());
+            }
+        } else {
+            if helper.verbose {
+                eprintln!("Uploading chunk {}/{}", i + 1, chunks.len());
+            }
+            call_ic(
+                helper,
+                "upload_chunk",
+                record(vec![canister(), ("chunk", IDLValue::Blob(chunk.to_vec()))]),
+            )?;
+        }
+        hashes.push(record(vec![("hash", IDLValue::Blob(hash))]));
+    }
+    let res = call_ic(
+        helper,
+        "install_chunked_code",
+        record(vec![
+            ("mode", mode),
+            ("target_canister", IDLValue::Principal(canister_id)),
+            ("store_canister", IDLValue::None),
+            ("chunk_hashes_list", IDLValue::Vec(hashes)),
+            ("wasm_module_hash", IDLValue::Blob(module_hash.clone())),
+            ("arg", IDLValue::Blob(arg.to_vec())),
+            ("sender_canister_version", IDLValue::None),
+        ]),
+    );
+    // Clear the uploaded chunks even when the install fails, and report the install error first.
+    let cleared = call_ic(helper, "clear_chunk_store", record(vec![canister()]));
+    res?;
+    cleared?;
+    Ok(module_hash)
+}
+
+/// Check the Candid interface before an upgrade sent with `call ic.install_code`.
+pub fn check_install_code(helper: &MyHelper, args: &IDLArgs) -> Result<()> {
+    let [IDLValue::Record(fs)] = args.args.as_slice() else {
+        return Ok(());
+    };
+    let canister_id = match get_field(fs, "canister_id") {
+        Some(IDLValue::Principal(id) | IDLValue::Service(id)) => *id,
+        _ => return Ok(()),
+    };
+    let (Some(mode), Some(wasm)) = (
+        get_field(fs, "mode"),
+        get_field(fs, "wasm_module").and_then(as_blob),
+    ) else {
+        return Ok(());
+    };
+    if !is_upgrade(mode) {
+        return Ok(());
+    }
+    if helper.offline.is_some() {
+        eprintln!("Warning: skip interface check in offline mode.");
+        return Ok(());
+    }
+    assert_upgrade(helper, canister_id, &wasm)
+}
+
+fn is_upgrade(mode: &IDLValue) -> bool {
+    matches!(mode, IDLValue::Variant(v) if v.0.id.get_id() == candid::idl_hash("upgrade"))
+}
+
+fn chunk_hash(v: IDLValue) -> Option<Vec<u8>> {
+    match v {
+        IDLValue::Record(fs) => as_blob(get_field(&fs, "hash")?),
+        _ => None,
+    }
+}
+
+pub fn install_mode(v: Option<&IDLValue>) -> Result<IDLValue> {
+    match v {
+        None => Ok(variant("install", IDLValue::Null)),
+        Some(v @ IDLValue::Variant(_)) => Ok(v.clone()),
+        Some(_) => Err(anyhow!(
+            "install mode expects a canister_install_mode variant"
+        )),
+    }
+}
+
+/// Create a canister with `provisional_create_canister_with_cycles`, or on mainnet, with the cycles
+/// ledger paid from the current identity's balance.
+/// `opts` can contain `settings` (canister_settings) and `cycles` (nat), which is required on mainnet.
+pub fn create_canister(helper: &MyHelper, opts: Option<&IDLValue>) -> Result<Principal> {
+    let fs = match opts {
+        Some(IDLValue::Record(fs)) => fs.as_slice(),
+        _ => &[],
+    };
+    let settings = match get_field(fs, "settings") {
+        Some(v @ (IDLValue::Opt(_) | IDLValue::None)) => v.clone(),
+        Some(v) => IDLValue::Opt(Box::new(v.clone())),
+        None => IDLValue::None,
+    };
+    if crate::helper::is_mainnet(&helper.agent_url) {
+        let amount = get_field(fs, "cycles").ok_or_else(|| {
+            anyhow!("create_canister on mainnet requires cycles to pay from the cycles ledger")
+        })?;
+        let res = call_canister(
+            helper,
+            Principal::from_text(CYCLES_LEDGER)?,
+            "create_canister",
+            record(vec![
+                ("from_subaccount", IDLValue::None),
+                ("created_at_time", IDLValue::None),
+                ("amount", amount.clone()),
+                (
+                    "creation_args",
+                    IDLValue::Opt(Box::new(record(vec![
+                        ("settings", settings),
+                        ("subnet_selection", IDLValue::None),
+                    ]))),
+                ),
+            ]),
+            Vec::new(),
+        )?;
+        return match res {
+            IDLValue::Variant(v) if v.0.id.get_id() == candid::idl_hash("Ok") => {
+                created_canister_id(&v.0.val)
+            }
+            IDLValue::Variant(v) => Err(anyhow!("create_canister fails: {}", v.0.val)),
+            _ => Err(anyhow!(
+                "create_canister cannot get the canister id in offline mode"
+            )),
+        };
+    }
+    let amount = match get_field(fs, "cycles") {
+        Some(v) => IDLValue::Opt(Box::new(v.clone())),
+        None => IDLValue::None,
+    };
+    let res = call_ic(
+        helper,
+        "provisional_create_canister_with_cycles",
+        record(vec![
+            ("settings", settings),
+            ("amount", amount),
+            ("specified_id", IDLValue::None),
+            ("sender_canister_version", IDLValue::None),
+        ]),
+    )?;
+    match res {
+        IDLValue::Record(_) => created_canister_id(&res),
+        _ => Err(anyhow!(
+            "create_canister cannot get the canister id in offline mode"
+        )),
+    }
+}
+
+fn created_canister_id(res: &IDLValue) -> Result<Principal> {
+    match res {
+        IDLValue::Record(fs) => match get_field(fs, "canister_id") {
+            Some(IDLValue::Principal(id)) => {
+                println!("Created canister {id}");
+                Ok(*id)
+            }
+            _ => Err(anyhow!("unexpected result from create_canister")),
+        },
+        _ => Err(anyhow!("unexpected result from create_canister")),
+    }
+}
+
+/// Install, reinstall or upgrade a canister. `opts` can contain
+/// * `arg`: init args. A blob value is used as the encoded args, other values are encoded with the
+///   candid:args metadata from the Wasm module.
+/// * `gzip`: gzip the module before installing.
+/// * `skip_pre_upgrade` and `wasm_memory_persistence`: upgrade options.
+/// * `force`: upgrade even if the installed module hash is the same as the new module.
+/// * `skip_check`: upgrade without checking the Candid interface compatibility.
+pub fn install_canister(
+    helper: &MyHelper,
+    canister_id: Principal,
+    wasm: &[u8],
+    mode: &str,
+    opts: Option<&IDLValue>,
+) -> Result<Vec<u8>> {
+    let fs = match opts {
+        Some(IDLValue::Record(fs)) => fs.as_slice(),
+        None => &[],
+        Some(_) => return Err(anyhow!("{mode} expects a record for options")),
+    };
+    let get_bool = |name| match get_field(fs, name) {
+        None => Ok(None),
+        Some(IDLValue::Bool(b)) => Ok(Some(*b)),
+        Some(_) => Err(anyhow!("{name} expects a bool")),
+    };
+    let arg = match get_field(fs, "arg") {
+        Some(IDLValue::Blob(blob)) => blob.clone(),
+        arg => {
+            let args = IDLArgs {
+                args: arg.into_iter().cloned().collect(),
+            };
+            match crate::exp::wasm_init_args(wasm)? {
+                Some((env, func)) => args.to_bytes_with_types(&env, &func.args)?,
+                None => args.to_bytes()?,
+            }
+        }
+    };
+    let wasm = if get_bool("gzip")? == Some(true) && !wasm.starts_with(&[0x1f, 0x8b]) {
+        gzip(wasm)?
+    } else {
+        wasm.to_vec()
+    };
+    let install_mode = canister_install_mode(mode, get_bool("skip_pre_upgrade")?, fs)?;
+    if mode == "upgrade" && get_bool("force")? != Some(true) {
+        let new_hash = Sha256::digest(&wasm).to_vec();
+        if module_hash(helper, canister_id)?.as_ref() == Some(&new_hash) {
+            println!("Module hash unchanged for {canister_id}, skip upgrade");
+            return Ok(new_hash);
+        }
+    }
+    let check = get_bool("skip_check")? != Some(true);
+    install(helper, canister_id, &wasm, &arg, install_mode, check)
+}
+
+/// Build the `canister_install_mode` variant for `install`, `reinstall` or `upgrade`.
+fn canister_install_mode(
+    mode: &str,
+    skip_pre_upgrade: Option<bool>,
+    fs: &[candid::types::value::IDLField],
+) -> Result<IDLValue> {
+    Ok(match mode {
+        "install" | "reinstall" => variant(mode, IDLValue::Null),
+        "upgrade" => {
+            let mut upgrade = Vec::new();
+            if let Some(skip) = skip_pre_upgrade {
+                upgrade.push((
+                    "skip_pre_upgrade",
+                    IDLValue::Opt(Box::new(IDLValue::Bool(skip))),
+                ));
+            }
+            if let Some(v) = get_field(fs, "wasm_memory_persistence") {
+                upgrade.push((
+                    "wasm_memory_persistence",
+                    IDLValue::Opt(Box::new(v.clone())),
+                ));
+            }
+            let upgrade = if upgrade.is_empty() {
+                IDLValue::None
+            } else {
+                IDLValue::Opt(Box::new(record(upgrade)))
+            };
+            variant(mode, upgrade)
+        }
+        _ => return Err(anyhow!("unknown install mode {mode}")),
+    })
+}
+
+/// Get the installed module hash from `canister_status`. Returns None in offline mode.
+pub fn module_hash(helper: &MyHelper, canister_id: Principal) -> Result<Option<Vec<u8>>> {
+    if helper.offline.is_some() {
+        return Ok(None);
+    }
+    let status = call_ic(
+        helper,
+        "canister_status",
+        record(vec![("canister_id", IDLValue::Principal(canister_id))]),
+    )?;
+    Ok(match status {
+        IDLValue::Record(fs) => match get_field(&fs, "module_hash") {
+            Some(IDLValue::Opt(hash)) => as_blob(hash),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+/// Call `start_canister`, `stop_canister` or `delete_canister`. Deleting a canister stops it first.
+pub fn set_canister_status(helper: &MyHelper, canister_id: Principal, method: &str) -> Result<()> {
+    let arg = || record(vec![("canister_id", IDLValue::Principal(canister_id))]);
+    if method == "delete_canister" {
+        call_ic(helper, "stop_canister", arg())?;
+    }
+    call_ic(helper, method, arg())?;
+    Ok(())
+}
+
+/// Upgrade a canister with a snapshot as a safety net: stop, take a snapshot, upgrade, start and
+/// run `check` on the canister id. If any step after the snapshot fails, or `check` returns false,
+/// the snapshot is loaded back and the canister restarted. Returns the snapshot id.
+pub fn safe_upgrade(
+    helper: &MyHelper,
+    canister_id: Principal,
+    wasm: &[u8],
+    opts: Option<&IDLValue>,
+    check: &str,
+) -> Result<Vec<u8>> {
+    if helper.offline.is_some() {
+        return Err(anyhow!("safe_upgrade is not supported in offline mode"));
+    }
+    if !helper.func_env.0.contains_key(check) {
+        return Err(anyhow!("Unknown function {check}"));
+    }
+    let canister = || ("canister_id", IDLValue::Principal(canister_id));
+    set_canister_status(helper, canister_id, "stop_canister")?;
+    let snapshot = call_ic(
+        helper,
+        "take_canister_snapshot",
+        record(vec![canister(), ("replace_snapshot", IDLValue::None)]),
+    );
+    let snapshot_id = match snapshot {
+        Ok(IDLValue::Record(fs)) => get_field(&fs, "id").and_then(as_blob),
+        Ok(_) => None,
+        Err(e) => {
+            set_canister_status(helper, canister_id, "start_canister")?;
+            return Err(e);
+        }
+    }
+    .ok_or_else(|| anyhow!("unexpected result from take_canister_snapshot"))?;
+    println!("Took snapshot {}", hex::encode(&snapshot_id));
+    let res = install_canister(helper, canister_id, wasm, "upgrade", opts)
+        .and_then(|_| set_canister_status(helper, canister_id, "start_canister"))
+        .and_then(|_| {
+            match crate::exp::apply_func(helper, check, vec![IDLValue::Principal(canister_id)])? {
+                IDLValue::Bool(false) => Err(anyhow!("{check} returns false")),
+                _ => Ok(()),
+            }
+        });
+    let Err(e) = res else {
+        return Ok(snapshot_id);
+    };
+    eprintln!(
+        "Upgrade failed: {e}\nRolling back to snapshot {}",
+        hex::encode(&snapshot_id)
+    );
+    // The canister can be running or stopped depending on the failed step.
+    set_canister_status(helper, canister_id, "stop_canister")?;
+    call_ic(
+        helper,
+        "load_canister_snapshot",
+        record(vec![
+            canister(),
+            ("snapshot_id", IDLValue::Blob(snapshot_id.clone())),
+            ("sender_canister_version", IDLValue::None),
+        ]),
+    )?;
+    set_canister_status(helper, canister_id, "start_canister")?;
+    Err(e.context(format!(
+        "upgrade of {canister_id} is rolled back to snapshot {}",
+        hex::encode(&snapshot_id)
+    )))
+}
+
+/// List snapshots of a canister with readable timestamps and sizes.
+pub fn list_snapshots(helper: &MyHelper, canister_id: Principal) -> Result<IDLValue> {
+    use crate::utils::{format_size, format_timestamp};
+    let res = call_ic(
+        helper,
+        "list_canister_snapshots",
+        record(vec![("canister_id", IDLValue::Principal(canister_id))]),
+    )?;
+    let IDLValue::Vec(snapshots) = res else {
+        return Ok(res);
+    };
+    let as_u64 = |v: Option<&IDLValue>| match v {
+        Some(IDLValue::Nat64(n)) => *n,
+        _ => 0,
+    };
+    let snapshots = snapshots
+        .into_iter()
+        .map(|v| match v {
+            IDLValue::Record(fs) => record(vec![
+                (
+                    "id",
+                    get_field(&fs, "id").cloned().unwrap_or(IDLValue::Null),
+                ),
+                (
+                    "taken_at",
+                    IDLValue::Text(format_timestamp(as_u64(get_field(
+                        &fs,
+                        "taken_at_timestamp",
+                    )))),
+                ),
+                (
+                    "total_size",
+                    IDLValue::Text(format_size(as_u64(get_field(&fs, "total_size")))),
+                ),
+            ]),
+            v => v,
+        })
+        .collect();
+    Ok(IDLValue::Vec(snapshots))
+}
+
+struct LogRecord {
+    idx: u64,
+    timestamp: u64,
+    content: String,
+}
+
+impl LogRecord {
+    fn to_value(&self) -> IDLValue {
+        record(vec![
+            ("idx", IDLValue::Nat64(self.idx)),
+            (
+                "timestamp",
+                IDLValue::Text(crate::utils::format_timestamp(self.timestamp)),
+            ),
+            ("content", IDLValue::Text(self.content.clone())),
+        ])
+    }
+}
+
+impl std::fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = crate::utils::format_timestamp(self.timestamp);
+        write!(f, "[{}. {}]: {}", self.idx, timestamp, self.content)
+    }
+}
+
+/// Filters for canister logs. Index and time bounds are inclusive.
+#[derive(Default)]
+struct LogFilter {
+    from_idx: Option<u64>,
+    to_idx: Option<u64>,
+    since: Option<u64>,
+    until: Option<u64>,
+    regex: Option<regex::Regex>,
+}
+
+impl LogFilter {
+    fn matches(&self, r: &LogRecord) -> bool {
+        if self.from_idx.is_some_and(|i| r.idx < i) || self.to_idx.is_some_and(|i| r.idx > i) {
+            return false;
+        }
+        if self.since.is_some_and(|t| r.timestamp < t)
+            || self.until.is_some_and(|t| r.timestamp > t)
+        {
+            return false;
+        }
+        match &self.regex {
+            Some(re) => re.is_match(&r.content),
+            None => true,
+        }
+    }
+}
+
+/// Fetch canister logs. `opts` can contain `from_idx`, `to_idx`, `since` and `until` (nanoseconds since
+/// the UNIX epoch), `regex`, and `follow`, which keeps polling every `interval` milliseconds and
+/// prints new records until interrupted.
+pub fn logs(
+    helper: &MyHelper,
+    canister_id: Principal,
+    opts: Option<&IDLValue>,
+) -> Result<IDLValue> {
+    let fs = match opts {
+        Some(IDLValue::Record(fs)) => fs.as_slice(),
+        None => &[],
+        Some(_) => return Err(anyhow!("logs expects a record for options")),
+    };
+    let get_u64 =
+        |name| -> Result<Option<u64>> { get_field(fs, name).map(crate::utils::as_u64).transpose() };
+    let filter = LogFilter {
+        from_idx: get_u64("from_idx")?,
+        to_idx: get_u64("to_idx")?,
+        since: get_u64("since")?,
+        until: get_u64("until")?,
+        regex: match get_field(fs, "regex") {
+            Some(IDLValue::Text(re)) => Some(regex::Regex::new(re)?),
+            Some(_) => return Err(anyhow!("regex expects a text")),
+            None => None,
+        },
+    };
+    let follow = matches!(get_field(fs, "follow"), Some(IDLValue::Bool(true)));
+    if !follow {
+        let records = fetch_logs(helper, canister_id)?
+            .into_iter()
+            .filter(|r| filter.matches(r))
+            .map(|r| r.to_value())
+            .collect();
+        return Ok(IDLValue::Vec(records));
+    }
+    if helper.offline.is_some() {
+        return Err(anyhow!("cannot follow logs in offline mode"));
+    }
+    let interval = std::time::Duration::from_millis(get_u64("interval")?.unwrap_or(2000));
+    // Ctrl-C stops following instead of ic-repl. The future is kept across polls, so that
+    // Ctrl-C pressed while fetching the logs is not lost.
+    let mut ctrl_c = Box::pin(tokio::signal::ctrl_c());
+    let mut last_idx = None;
+    loop {
+        for r in fetch_logs(helper, canister_id)? {
+            if last_idx.is_some_and(|i| r.idx <= i) {
+                continue;
+            }
+            last_idx = Some(r.idx);
+            if filter.matches(&r) {
+                println!("{r}");
+            }
+        }
+        let interrupted = helper.block_on(async {
+            tokio::select! {
+                res = &mut ctrl_c => res.is_ok(),
+                _ = tokio::time::sleep(interval) => false,
+            }
+        });
+        if interrupted {
+            return Ok(IDLValue::Null);
+        }
+    }
+}
+
+fn fetch_logs(helper: &MyHelper, canister_id: Principal) -> Result<Vec<LogRecord>> {
+    let res = call_ic(
+        helper,
+        "fetch_canister_logs",
+        record(vec![("canister_id", IDLValue::Principal(canister_id))]),
+    )?;
+    let IDLValue::Record(fs) = res else {
+        // offline mode doesn't return anything
+        return Ok(Vec::new());
+    };
+    let Some(IDLValue::Vec(records)) = get_field(&fs, "canister_log_records") else {
+        return Err(anyhow!("unexpected result from fetch_canister_logs"));
+    };
+    records
+        .iter()
+        .map(|r| {
+            let IDLValue::Record(fs) = r else {
+                return Err(anyhow!("unexpected canister_log_record"));
+            };
+            let u64_field = |name| match get_field(fs, name) {
+                Some(IDLValue::Nat64(n)) => Ok(*n),
+                _ => Err(anyhow!("unexpected canister_log_record")),
+            };
+            let content = get_field(fs, "content")
+                .and_then(as_blob)
+                .ok_or_else(|| anyhow!("unexpected canister_log_record"))?;
+            let content = match String::from_utf8(content) {
+                Ok(text) => text,
+                Err(e) => IDLValue::Blob(e.into_bytes()).to_string(),
+            };
+            Ok(LogRecord {
+                idx: u64_field("idx")?,
+                timestamp: u64_field("timestamp_nanos")?,
+                content,
+            })
+        })
+        .collect()
+}
+
+/// Check that upgrading `canister_id` to `wasm` doesn't break the Candid interface.
+/// Returns the list of breaking changes, which is empty if the upgrade is compatible.
+pub fn check_upgrade(
+    helper: &MyHelper,
+    canister_id: Principal,
+    wasm: &[u8],
+) -> Result<Vec<String>> {
+    use ic_wasm::{metadata::get_metadata, utils::parse_wasm};
+    let wasm = crate::utils::gunzip_if_needed(wasm)?;
+    let m = parse_wasm(&wasm, false)?;
+    let new_metadata = |name| {
+        get_metadata(&m, name)
+            .map(|bytes| String::from_utf8(bytes.into_owned()))
+            .transpose()
+    };
+    let new_candid = new_metadata("candid:service")?;
+    let new_stable = new_metadata("motoko:stable-types")?;
+    let (old_candid, old_stable) =
+        helper.block_on(fetch_installed_interface(&helper.agent, canister_id));
+    let mut errors = Vec::new();
+    match (old_candid, new_candid) {
+        (Some(old), Some(new)) => errors.extend(service_changes(&old, &new)?),
+        (None, _) => eprintln!(
+            "Warning: cannot fetch candid:service of {canister_id}, skip interface check."
+        ),
+        (_, None) => {
+            eprintln!(
+                "Warning: no candid:service metadata in the Wasm module, skip interface check."
+            )
+        }
+    }
+    if let (Some(old), Some(new)) = (old_stable, new_stable) {
+        // Precise stable type compatibility requires moc, so we only report dropped or changed variables.
+        let new = stable_vars(&new);
+        for (name, ty) in stable_vars(&old) {
+            match new.iter().find(|(n, _)| *n == name) {
+                None => eprintln!("Warning: stable variable {name} is dropped"),
+                Some((_, new_ty)) if *new_ty != ty => {
+                    eprintln!("Warning: stable variable {name} changes type from {ty} to {new_ty}")
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(errors)
+}
+
+/// Fail with every breaking change if upgrading `canister_id` to `wasm` is not compatible.
+pub fn assert_upgrade(helper: &MyHelper, canister_id: Principal, wasm: &[u8]) -> Result<()> {
+    let errors = check_upgrade(helper, canister_id, wasm)?;
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Upgrading {canister_id} breaks the Candid interface:\n  {}",
+            errors.join("\n  ")
+        ))
+    }
+}
+
+/// Report every method in the old service whose type in the new service is not a subtype.
+fn service_changes(old: &str, new: &str) -> Result<Vec<String>> {
+    use candid_parser::utils::{instantiate_candid, CandidSource};
+    let (_, (mut env, new)) = instantiate_candid(CandidSource::Text(new))?;
+    let (_, (env2, old)) = instantiate_candid(CandidSource::Text(old))?;
+    let old = env.merge_type(env2, old);
+    let new_methods = env.as_service(&new)?.to_vec();
+    let mut errors = Vec::new();
+    for (name, old_ty) in env.as_service(&old)? {
+        match new_methods.iter().find(|(n, _)| n == name) {
+            None => errors.push(format!("method {name} is removed")),
+            Some((_, new_ty)) => {
+                let mut gamma = std::collections::HashSet::new();
+                if let Err(e) = candid::types::subtype::subtype(&mut gamma, &env, new_ty, old_ty) {
+                    errors.push(format!("method {name} changes type incompatibly: {e}"));
+                }
+            }
+        }
+    }
+    Ok(errors)
+}
+
+/// Split `actor { stable x : T; stable var y : U }` into variable names and normalized types.
+fn stable_vars(stable_types: &str) -> Vec<(String, String)> {
+    let Some(body) = stable_types
+        .rfind("actor")
+        .and_then(|i| {
+            stable_types[i..]
+                .find('{')
+                .map(|j| &stable_types[i + j + 1..])
+        })
+        .and_then(|s| s.rfind('}').map(|j| &s[..j]))
+    else {
+        return Vec::new();
+    };
+    let mut items = Vec::new();
+    let (mut depth, mut start) = (0i32, 0);
+    let mut prev = None;
+    for (i, c) in body.char_indices() {
+        match c {
+            // the `>` in a function type `->` is not a closing bracket
+            '>' if prev == Some('-') => (),
+            '{' | '(' | '[' | '<' => depth += 1,
+            '}' | ')' | ']' | '>' => depth -= 1,
+            ';' if depth == 0 => {
+                items.push(&body[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+        prev = Some(c);
+    }
+    items.push(&body[start..]);
+    items
+        .into_iter()
+        .filter_map(|item| {
+            let item = item.split_whitespace().collect::<Vec<_>>().join(" ");
+            let item = item.strip_prefix("stable ")?;
+            let item = item.strip_prefix("var ").unwrap_or(item);
+            let (name, ty) = item.split_once(':')?;
+            Some((name.trim().to_string(), ty.trim().to_string()))
+        })
+        .collect()
+}
+
+async fn fetch_installed_interface(
+    agent: &Agent,
+    canister_id: Principal,
+) -> (Option<String>, Option<String>) {
+    let fetch = |name| async move {
+        let path = format!("metadata/{name}");
+        fetch_metadata(agent, canister_id, &path)
+            .await
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+    };
+    (
+        fetch("candid:service").await,
+        fetch("motoko:stable-types").await,
+    )
+}
+
+#[test]
+fn test_service_changes() {
+    let old = "type T = record { a : int }; service : { f : (T) -> (); g : () -> (text) query; h : () -> () }";
+    let new = "type T = record { a : nat }; service : { f : (T) -> (); g : () -> (text) query; i : () -> () }";
+    let errors = service_changes(old, new).unwrap();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("method f "));
+    assert_eq!(errors[1], "method h is removed");
+    assert!(service_changes(old, old).unwrap().is_empty());
+    let vars = stable_vars("// Version: 1.0.0\nactor {\n  stable var map : [(Nat, {a : Nat; b : Text})];\n  stable x : Nat\n};\n");
+    assert_eq!(vars[0].0, "map");
+    assert_eq!(vars[1], ("x".to_string(), "Nat".to_string()));
+    let vars = stable_vars("actor {\n  stable var handlers : [(Text, shared Nat -> async ())];\n  stable y : <T>(T) -> T\n};\n");
+    assert_eq!(vars.len(), 2);
+    assert_eq!(vars[0].1, "[(Text, shared Nat -> async ())]");
+    assert_eq!(vars[1], ("y".to_string(), "<T>(T) -> T".to_string()));
+}
+
+#[test]
+fn test_install_args() {
+    use ic_wasm::metadata::{add_metadata, Kind};
+    let mode = canister_install_mode("upgrade", Some(true), &[]).unwrap();
+    assert!(is_upgrade(&mode));
+    assert_eq!(
+        mode.to_string(),
+        "variant { upgrade = opt record { skip_pre_upgrade = opt true } }"
+    );
+    assert!(!is_upgrade(
+        &canister_install_mode("install", None, &[]).unwrap()
+    ));
+    assert!(canister_install_mode("deploy", None, &[]).is_err());
+    let wasm = |args: &str| {
+        let mut m = ic_wasm::utils::parse_wasm(b"\0asm\x01\0\0\0", false).unwrap();
+        add_metadata(
+            &mut m,
+            Kind::Public,
+            "candid:args",
+            args.as_bytes().to_vec(),
+        );
+        m.emit_wasm()
+    };
+    let (_, func) = crate::exp::wasm_init_args(&wasm("(nat, text)"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(func.args.len(), 2);
+    assert!(crate::exp::wasm_init_args(&wasm("(nat")).is_err());
+}
+
+#[test]
+fn test_log_filter() {
+    let record = |idx, content: &str| LogRecord {
+        idx,
+        timestamp: idx * 1000,
+        content: content.to_string(),
+    };
+    let filter = LogFilter {
+        from_idx: Some(2),
+        until: Some(5000),
+        regex: Some(regex::Regex::new("^err").unwrap()),
+        ..Default::default()
+    };
+    assert!(filter.matches(&record(2, "error")));
+    assert!(!filter.matches(&record(1, "error")));
+    assert!(!filter.matches(&record(6, "error")));
+    assert!(!filter.matches(&record(3, "no error")));
+    assert_eq!(
+        record(1, "hello").to_string(),
+        "[1. 1970-01-01T00:00:00.000001Z]: hello"
+    );
+}
@@ -1,9 +1,10 @@
+use crate::call_log::{CallLog, LogEntry};
 use crate::helper::{MyHelper, OfflineOutput};
 use crate::utils::args_to_value;
 use anyhow::{anyhow, Context, Result};
 use candid::Principal;
 use candid::{types::Function, IDLArgs, TypeEnv};
-use ic_agent::{agent::CallResponse, Agent};
+use ic_agent::{agent::CallResponse, Agent, RequestId};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,8 +24,42 @@ pub struct IngressWithStatus {
     pub ingress: Ingress,
     pub request_status: Option<RequestStatus>,
 }
+/// The network that the messages are signed for.
 #[derive(Serialize, Deserialize, Clone)]
-pub struct Messages(Vec<IngressWithStatus>);
+pub struct OfflineNetwork {
+    pub name: String,
+    pub url: String,
+    /// Hex encoded root key from `--root-key`, checked against the replica when sending
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_key: Option<String>,
+}
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(from = "MessagesFormat")]
+pub struct Messages {
+    pub network: Option<OfflineNetwork>,
+    pub messages: Vec<IngressWithStatus>,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessagesFormat {
+    Current {
+        network: Option<OfflineNetwork>,
+        messages: Vec<IngressWithStatus>,
+    },
+    /// A plain list of messages, written by older versions
+    Legacy(Vec<IngressWithStatus>),
+}
+impl From<MessagesFormat> for Messages {
+    fn from(format: MessagesFormat) -> Self {
+        match format {
+            MessagesFormat::Current { network, messages } => Messages { network, messages },
+            MessagesFormat::Legacy(messages) => Messages {
+                network: None,
+                messages,
+            },
+        }
+    }
+}
 
 static mut PNG_COUNTER: u32 = 0;
 
@@ -114,10 +149,13 @@ pub fn output_message(json: String, format: &OfflineOutput) -> Result<()> {
     };
     Ok(())
 }
-pub fn dump_ingress(msgs: &[IngressWithStatus]) -> Result<()> {
+pub fn dump_ingress(msgs: &[IngressWithStatus], network: OfflineNetwork) -> Result<()> {
     use std::fs::File;
     use std::io::Write;
-    let msgs = Messages(msgs.to_vec());
+    let msgs = Messages {
+        network: Some(network),
+        messages: msgs.to_vec(),
+    };
     let json = serde_json::to_string(&msgs)?;
     let mut file = File::create("messages.json")?;
     file.write_all(json.as_bytes())?;
@@ -125,16 +163,43 @@ pub fn dump_ingress(msgs: &[IngressWithStatus]) -> Result<()> {
 }
 
 pub fn send_messages(helper: &MyHelper, msgs: &Messages) -> Result<IDLArgs> {
-    let len = msgs.0.len();
+    if let Some(network) = &msgs.network {
+        check_network(helper, network)?;
+    }
+    let len = msgs.messages.len();
     let mut res = Vec::with_capacity(len);
     println!("Sending {} messages to {}", len, helper.agent_url);
-    for (i, msg) in msgs.0.iter().enumerate() {
+    for (i, msg) in msgs.messages.iter().enumerate() {
         print!("[{}/{}] ", i + 1, len);
         let args = send(helper, msg)?;
         res.push(args_to_value(args))
     }
     Ok(IDLArgs::new(&res))
 }
+/// Refuse to send messages signed for another replica.
+fn check_network(helper: &MyHelper, network: &OfflineNetwork) -> Result<()> {
+    use crate::helper::is_mainnet;
+    let same_url = network.url.trim_end_matches('/') == helper.agent_url.trim_end_matches('/')
+        || is_mainnet(&network.url) && is_mainnet(&helper.agent_url);
+    if !same_url {
+        return Err(anyhow!(
+            "Messages are signed for network {} ({}), but the replica is {}. Use --replica {}",
+            network.name,
+            network.url,
+            helper.agent_url,
+            network.url
+        ));
+    }
+    if let Some(root_key) = &network.root_key {
+        if hex::decode(root_key)? != helper.agent.read_root_key() {
+            return Err(anyhow!(
+                "The root key of {} doesn't match the root key the messages are signed with",
+                helper.agent_url
+            ));
+        }
+    }
+    Ok(())
+}
 pub fn send(helper: &MyHelper, msg: &IngressWithStatus) -> Result<IDLArgs> {
     let message = &msg.ingress;
     let (sender, canister_id, method_name, bytes) = message.parse()?;
@@ -156,40 +221,87 @@ pub fn send(helper: &MyHelper, msg: &IngressWithStatus) -> Result<IDLArgs> {
     if !["y", "yes"].contains(&input.to_lowercase().trim()) {
         return Err(anyhow!("Send abort"));
     }
-    send_internal(&helper.agent, canister_id, msg, &opt_func)
+    let effective_id = match &msg.request_status {
+        Some(status) => status.canister_id,
+        None => crate::utils::get_effective_canister_id(canister_id, &method_name, &bytes)?
+            .unwrap_or(helper.default_effective_canister_id),
+    };
+    let log = helper.call_log.as_ref().map(|log| {
+        let types = opt_func.as_ref().map(|(env, f)| (env, f.args.as_slice()));
+        let mode = if message.call_type == "query" {
+            "query"
+        } else {
+            "update"
+        };
+        let entry = log
+            .entry(
+                &helper.agent,
+                canister_id,
+                &method_name,
+                mode,
+                Some(effective_id),
+            )
+            .sender(sender)
+            .args(&bytes, types);
+        (log, entry)
+    });
+    helper.block_on(send_internal(
+        &helper.agent,
+        effective_id,
+        msg,
+        &opt_func,
+        log,
+    ))
 }
-#[tokio::main]
 async fn send_internal(
     agent: &Agent,
-    canister_id: Principal,
+    effective_id: Principal,
     message: &IngressWithStatus,
     opt_func: &Option<(TypeEnv, Function)>,
+    log: Option<(&CallLog, LogEntry)>,
 ) -> Result<IDLArgs> {
     let content = hex::decode(&message.ingress.content)?;
-    let response = match message.ingress.call_type.as_str() {
-        "query" => agent.query_signed(canister_id, content).await?,
-        "update" => {
-            let call_response = agent.update_signed(canister_id, content).await?;
-            match call_response {
-                CallResponse::Response(blob) => blob,
-                CallResponse::Poll(request_id) => {
-                    println!("Request ID: 0x{}", String::from(request_id));
-                    let status = message
-                        .request_status
-                        .as_ref()
-                        .ok_or_else(|| anyhow!("Cannot get request status for update call"))?;
-                    if !(status.canister_id == canister_id
-                        && status.request_id == String::from(request_id))
-                    {
-                        return Err(anyhow!("request_id doesn't match, cannot request status"));
+    let mut request_id = message
+        .ingress
+        .request_id
+        .as_deref()
+        .and_then(|id| id.parse::<RequestId>().ok());
+    let response: Result<Vec<u8>> = async {
+        Ok(match message.ingress.call_type.as_str() {
+            "query" => agent.query_signed(effective_id, content).await?,
+            "update" => {
+                let call_response = agent.update_signed(effective_id, content).await?;
+                match call_response {
+                    CallResponse::Response(blob) => blob,
+                    CallResponse::Poll(id) => {
+                        request_id = Some(id);
+                        println!("Request ID: 0x{}", String::from(id));
+                        let status = message
+                            .request_status
+                            .as_ref()
+                            .ok_or_else(|| anyhow!("Cannot get request status for update call"))?;
+                        if !(status.canister_id == effective_id
+                            && status.request_id == String::from(id))
+                        {
+                            return Err(anyhow!("request_id doesn't match, cannot request status"));
+                        }
+                        let status = hex::decode(&status.content)?;
+                        agent.wait_signed(&id, effective_id, status).await?.0
                     }
-                    let status = hex::decode(&status.content)?;
-                    agent.wait_signed(&request_id, canister_id, status).await?.0
                 }
             }
+            _ => unreachable!(),
+        })
+    }
+    .await;
+    if let Some((log, mut entry)) = log {
+        if let Some(request_id) = &request_id {
+            entry.set_request_id(request_id);
         }
-        _ => unreachable!(),
-    };
+        let rets = opt_func.as_ref().map(|(env, f)| (env, f.rets.as_slice()));
+        log.finish(entry, response.as_deref(), rets);
+    }
+    let response = response?;
     let res = if let Some((env, func)) = &opt_func {
         IDLArgs::from_bytes_with_types(&response, env, &func.rets)?
     } else {
@@ -198,3 +310,14 @@ async fn send_internal(
     println!("{res}");
     Ok(res)
 }
+
+#[test]
+fn test_messages_format() {
+    let msgs: Messages = serde_json::from_str("[]").unwrap();
+    assert!(msgs.network.is_none() && msgs.messages.is_empty());
+    let json = r#"{"network":{"name":"local","url":"http://localhost:4943/","root_key":"3081"},"messages":[]}"#;
+    let msgs: Messages = serde_json::from_str(json).unwrap();
+    let network = msgs.network.unwrap();
+    assert_eq!(network.url, "http://localhost:4943/");
+    assert_eq!(network.root_key.as_deref(), Some("3081"));
+}
@@ -16,7 +16,6 @@ pub fn ok_to_profile<'a>(helper: &'a MyHelper, info: &'a MethodInfo) -> bool {
         && info.signature.as_ref().map(|s| s.1.is_query()) != Some(true)
 }
 
-#[tokio::main]
 pub async fn get_cycles(agent: &Agent, canister_id: &Principal) -> anyhow::Result<i64> {
     get_cycles_inner(agent, canister_id).await
 }
@@ -31,7 +30,6 @@ async fn get_cycles_inner(agent: &Agent, canister_id: &Principal) -> anyhow::Res
     Ok(Decode!(&bytes, i64)?)
 }
 
-#[tokio::main]
 pub async fn get_profiling(
     agent: &Agent,
     canister_id: &Principal,
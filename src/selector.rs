@@ -14,6 +14,7 @@ pub enum Selector {
     Field(String),
     Option,
     Map(String),
+    ParMap(String, Option<Exp>),
     Filter(String),
     Fold(Exp, String),
     Size, // Size is not required, but it is faster than using fold
@@ -65,6 +66,9 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
             (IDLValue::Vec(vs), Selector::Map(func)) => {
                 result = IDLValue::Vec(map(helper, vs, &func)?);
             }
+            (IDLValue::Vec(vs), Selector::ParMap(func, limit)) => {
+                result = IDLValue::Vec(par_map(helper, vs, &func, limit)?);
+            }
             (IDLValue::Blob(b), Selector::Filter(func)) => {
                 let vs = b.into_iter().map(IDLValue::Nat8).collect();
                 result = IDLValue::Vec(filter(helper, vs, &func)?);
@@ -90,6 +94,11 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
                 let res = map(helper, vs, &func)?;
                 result = IDLValue::Record(to_field(res)?);
             }
+            (IDLValue::Record(fs), Selector::ParMap(func, limit)) => {
+                let vs = from_fields(fs);
+                let res = par_map(helper, vs, &func, limit)?;
+                result = IDLValue::Record(to_field(res)?);
+            }
             (IDLValue::Record(fs), Selector::Filter(func)) => {
                 let vs = from_fields(fs);
                 let res = filter(helper, vs, &func)?;
@@ -220,6 +229,56 @@ fn map(helper: &MyHelper, vs: Vec<IDLValue>, func: &str) -> Result<Vec<IDLValue>
     Ok(res)
 }
 
+/// Default number of workers for par_map
+const PAR_MAP_LIMIT: usize = 10;
+
+fn par_map(
+    helper: &MyHelper,
+    vs: Vec<IDLValue>,
+    func: &str,
+    limit: Option<Exp>,
+) -> Result<Vec<IDLValue>> {
+    let limit = match limit {
+        Some(e) => as_u32(&e.eval(helper)?)? as usize,
+        None => PAR_MAP_LIMIT,
+    };
+    helper.block_on(par_map_async(helper, vs, func, limit))
+}
+
+/// Evaluate `func` on each value as a future on the shared runtime, with at most `limit` in flight,
+/// and keep the results in order. Each evaluation has its own spawned helper, and the futures
+/// interleave on one thread while waiting for the replica, so the interpreter state is never shared
+/// across threads.
+async fn par_map_async(
+    helper: &MyHelper,
+    vs: Vec<IDLValue>,
+    func: &str,
+    limit: usize,
+) -> Result<Vec<IDLValue>> {
+    buffered_map(vs, limit, |v| apply_async(helper, func, v)).await
+}
+
+async fn apply_async(helper: &MyHelper, func: &str, v: IDLValue) -> Result<IDLValue> {
+    let mut helper = helper.spawn();
+    helper.env.0.insert(String::new(), v);
+    let arg = Exp::Path(String::new(), Vec::new());
+    Exp::Apply(func.to_string(), vec![arg])
+        .eval_async(&helper)
+        .await
+}
+
+async fn buffered_map<F, Fut>(vs: Vec<IDLValue>, limit: usize, f: F) -> Result<Vec<IDLValue>>
+where
+    F: FnMut(IDLValue) -> Fut,
+    Fut: std::future::Future<Output = Result<IDLValue>>,
+{
+    use futures::stream::{self, StreamExt, TryStreamExt};
+    stream::iter(vs.into_iter().map(f))
+        .buffered(limit.max(1))
+        .try_collect()
+        .await
+}
+
 fn filter(helper: &MyHelper, vs: Vec<IDLValue>, func: &str) -> Result<Vec<IDLValue>> {
     let mut new_helper = helper.spawn();
     let mut res = Vec::with_capacity(vs.len());
@@ -250,3 +309,44 @@ fn fold(helper: &MyHelper, init: Exp, vs: Vec<IDLValue>, func: &str) -> Result<I
     }
     Ok(acc)
 }
+
+#[test]
+fn test_par_map() -> Result<()> {
+    use crate::command::Commands;
+    use crate::helper::OfflineOutput;
+    use std::cell::Cell;
+    let url = "https://icp0.io".to_string();
+    let agent = ic_agent::Agent::builder().with_url(url.clone()).build()?;
+    let mut helper = MyHelper::new(agent, url, Some(OfflineOutput::Json), false);
+    let run = |helper: &mut MyHelper, script: &str| -> Result<()> {
+        for (cmd, _) in script.parse::<Commands>()?.0 {
+            cmd.run(helper)?;
+        }
+        Ok(())
+    };
+    run(
+        &mut helper,
+        "function f(x) { sleep(50); add(x, 1) }; let xs = vec { 1; 2; 3; 4; 5 }",
+    )?;
+    run(&mut helper, "let res = xs.par_map(f)")?;
+    let expected: Vec<_> = (2..=6).map(|n| IDLValue::Number(n.to_string())).collect();
+    assert_eq!(helper.env.0["res"], IDLValue::Vec(expected));
+    let err = run(&mut helper, "let res = xs.par_map(g)").unwrap_err();
+    assert!(err.to_string().contains("Unknown function g"));
+    // count the evaluations of f in flight at the same time
+    let peak = |limit: usize| -> Result<usize> {
+        let (running, peak) = (Cell::new(0), Cell::new(0));
+        let vs = (1..=5).map(|n| IDLValue::Number(n.to_string())).collect();
+        helper.block_on(buffered_map(vs, limit, |v| async {
+            running.set(running.get() + 1);
+            peak.set(peak.get().max(running.get()));
+            let res = apply_async(&helper, "f", v).await;
+            running.set(running.get() - 1);
+            res
+        }))?;
+        Ok(peak.get())
+    };
+    assert_eq!(peak(PAR_MAP_LIMIT)?, 5);
+    assert_eq!(peak(2)?, 2);
+    Ok(())
+}
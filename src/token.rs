@@ -19,6 +19,8 @@ pub enum Token {
     RSquare,
     #[token("?")]
     Question,
+    #[token("@")]
+    At,
     #[token("{")]
     LBrace,
     #[token("}")]
@@ -1,12 +1,17 @@
+use crate::call_log::CallLog;
 use crate::helper::MyHelper;
 use anyhow::{anyhow, Context, Result};
 use candid::pretty::candid::value::number_to_string;
-use candid::types::value::{IDLArgs, IDLField, IDLValue};
-use candid::types::{Label, Type, TypeInner};
+use candid::types::value::{IDLArgs, IDLField, IDLValue, VariantValue};
+use candid::types::{Function, Label, Type, TypeInner};
 use candid::{Principal, TypeEnv};
 use candid_parser::configs::Configs;
-use ic_agent::Agent;
+use ic_agent::{
+    agent::{RejectCode, RejectResponse},
+    Agent, AgentError,
+};
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use std::slice;
 
@@ -209,12 +214,210 @@ pub fn as_u32(v: &IDLValue) -> Result<u32> {
     }
 }
 
+pub fn as_u64(v: &IDLValue) -> Result<u64> {
+    match v {
+        IDLValue::Number(n) => Ok(n.parse::<u64>()?),
+        IDLValue::Nat64(n) => Ok(*n),
+        IDLValue::Nat(n) => Ok(n.0.clone().try_into()?),
+        _ => Err(anyhow!("{v} is not a number")),
+    }
+}
+
+pub fn gzip(blob: &[u8]) -> Result<Vec<u8>> {
+    use libflate::gzip::{EncodeOptions, Encoder, HeaderBuilder};
+    use std::io::Write;
+    // Fix the modification time, so that the module hash is deterministic
+    let header = HeaderBuilder::new().modification_time(0).finish();
+    let options = EncodeOptions::new().header(header);
+    let mut encoder = Encoder::with_options(Vec::with_capacity(blob.len()), options)?;
+    encoder.write_all(blob)?;
+    Ok(encoder.finish().into_result()?)
+}
+
+pub fn gunzip_if_needed(blob: &[u8]) -> Result<Cow<'_, [u8]>> {
+    use libflate::gzip::Decoder;
+    use std::io::Read;
+    if !blob.starts_with(&[0x1f, 0x8b]) {
+        return Ok(Cow::Borrowed(blob));
+    }
+    let mut decoder = Decoder::new(blob)?;
+    let mut res = Vec::new();
+    decoder.read_to_end(&mut res)?;
+    Ok(Cow::Owned(res))
+}
+
+/// Record the func type of every func value in `v`, so that the func reference can be called later.
+pub fn collect_func_types(
+    env: &TypeEnv,
+    ty: &Type,
+    v: &IDLValue,
+    res: &mut BTreeMap<(Principal, String), (TypeEnv, Function)>,
+) {
+    let Ok(ty) = env.trace_type(ty) else {
+        return;
+    };
+    match (ty.as_ref(), v) {
+        (TypeInner::Func(f), IDLValue::Func(id, method)) => {
+            res.entry((*id, method.clone()))
+                .or_insert_with(|| (env.clone(), f.clone()));
+        }
+        (TypeInner::Opt(t), IDLValue::Opt(v)) => collect_func_types(env, t, v, res),
+        (TypeInner::Vec(t), IDLValue::Vec(vs)) if has_func(env, t, &mut Vec::new()) => {
+            for v in vs {
+                collect_func_types(env, t, v, res);
+            }
+        }
+        (TypeInner::Record(fs), IDLValue::Record(vs)) => {
+            for v in vs {
+                if let Some(f) = fs.iter().find(|f| f.id.get_id() == v.id.get_id()) {
+                    collect_func_types(env, &f.ty, &v.val, res);
+                }
+            }
+        }
+        (TypeInner::Variant(fs), IDLValue::Variant(v)) => {
+            if let Some(f) = fs.iter().find(|f| f.id.get_id() == v.0.id.get_id()) {
+                collect_func_types(env, &f.ty, &v.0.val, res);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Check if a type contains func types, so that we can skip walking large vectors.
+fn has_func(env: &TypeEnv, ty: &Type, seen: &mut Vec<String>) -> bool {
+    match ty.as_ref() {
+        TypeInner::Var(id) => {
+            if seen.contains(id) {
+                return false;
+            }
+            seen.push(id.clone());
+            env.find_type(id).is_ok_and(|t| has_func(env, t, seen))
+        }
+        TypeInner::Func(_) => true,
+        TypeInner::Opt(t) | TypeInner::Vec(t) => has_func(env, t, seen),
+        TypeInner::Record(fs) | TypeInner::Variant(fs) => {
+            fs.iter().any(|f| has_func(env, &f.ty, seen))
+        }
+        _ => false,
+    }
+}
+
+/// Format nanoseconds since the UNIX epoch as an RFC 3339 timestamp in UTC.
+pub fn format_timestamp(nanos: u64) -> String {
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+    OffsetDateTime::from_unix_timestamp_nanos(nanos as i128)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| nanos.to_string())
+}
+
+/// Format a size in bytes with binary units, e.g., `1.50 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.2} {}", UNITS[unit])
+    }
+}
+
+pub fn as_blob(v: &IDLValue) -> Option<Vec<u8>> {
+    match v {
+        IDLValue::Blob(b) => Some(b.clone()),
+        IDLValue::Vec(vs) => vs
+            .iter()
+            .map(|v| match v {
+                IDLValue::Nat8(n) => Some(*n),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
 pub fn get_field<'a>(fs: &'a [IDLField], key: &'a str) -> Option<&'a IDLValue> {
     fs.iter()
         .find(|f| f.id == Label::Named(key.to_string()))
         .map(|f| &f.val)
 }
 
+pub fn get_reject(e: &anyhow::Error) -> Option<&RejectResponse> {
+    match e.downcast_ref::<AgentError>()? {
+        AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject) => Some(reject),
+        _ => None,
+    }
+}
+
+/// A call that didn't return within the `timeout` call option.
+#[derive(Debug)]
+pub struct CallTimeout(pub std::time::Duration);
+impl std::fmt::Display for CallTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "call timed out after {} ms", self.0.as_millis())
+    }
+}
+impl std::error::Error for CallTimeout {}
+
+/// Errors that may go away when retried: transport failures, timeouts, overloaded replicas and SysTransient rejects.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    if e.is::<CallTimeout>() {
+        return true;
+    }
+    match e.downcast_ref::<AgentError>() {
+        Some(AgentError::TransportError(_) | AgentError::TimeoutWaitingForResponse()) => true,
+        Some(AgentError::HttpError(payload)) => payload.status == 429 || payload.status >= 500,
+        Some(AgentError::CertifiedReject(reject) | AgentError::UncertifiedReject(reject)) => {
+            reject.reject_code == RejectCode::SysTransient
+        }
+        _ => false,
+    }
+}
+
+pub fn reject_to_value(reject: &RejectResponse) -> IDLValue {
+    let code = format!("{:?}", reject.reject_code);
+    let error_code = match &reject.error_code {
+        Some(code) => IDLValue::Opt(Box::new(IDLValue::Text(code.clone()))),
+        None => IDLValue::None,
+    };
+    record(vec![
+        ("reject_code", variant(&code, IDLValue::Null)),
+        (
+            "reject_message",
+            IDLValue::Text(reject.reject_message.clone()),
+        ),
+        ("error_code", error_code),
+    ])
+}
+
+/// Build a record value with named fields, sorted by field id.
+pub fn record(fields: Vec<(&str, IDLValue)>) -> IDLValue {
+    let mut fs: Vec<_> = fields
+        .into_iter()
+        .map(|(name, val)| IDLField {
+            id: Label::Named(name.to_string()),
+            val,
+        })
+        .collect();
+    fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+    IDLValue::Record(fs)
+}
+
+pub fn variant(tag: &str, val: IDLValue) -> IDLValue {
+    IDLValue::Variant(VariantValue(
+        Box::new(IDLField {
+            id: Label::Named(tag.to_string()),
+            val,
+        }),
+        0,
+    ))
+}
+
 pub fn args_to_value(mut args: IDLArgs) -> IDLValue {
     match args.args.len() {
         0 => IDLValue::Null,
@@ -262,8 +465,19 @@ pub fn get_dfx_hsm_pin() -> Result<String, String> {
     })
 }
 
-#[tokio::main]
-pub async fn fetch_state_path(agent: &Agent, mut path: StatePath) -> anyhow::Result<IDLValue> {
+pub fn get_pem_password(path: &Path) -> anyhow::Result<String> {
+    std::env::var("IC_REPL_PEM_PASSWORD").or_else(|_| {
+        rpassword::prompt_password(format!("Password for {}: ", path.display())).context(
+            "No IC_REPL_PEM_PASSWORD environment variable and cannot read password from tty",
+        )
+    })
+}
+
+pub async fn fetch_state_path(
+    agent: &Agent,
+    mut path: StatePath,
+    log: Option<&CallLog>,
+) -> anyhow::Result<IDLValue> {
     if path.effective_id.is_none() {
         let id = if path.path.len() >= 3
             && path.path[0] == "subnet".into()
@@ -281,7 +495,20 @@ pub async fn fetch_state_path(agent: &Agent, mut path: StatePath) -> anyhow::Res
         path.effective_id = Some(id);
         eprintln!("Using {id} as effective canister/subnet id. To change it, put the effective id as the first argument.");
     }
-    fetch_state_path_(agent, path).await
+    let Some(log) = log else {
+        return fetch_state_path_(agent, path).await;
+    };
+    let effective_id = path.effective_id.unwrap();
+    let entry = log.entry(
+        agent,
+        effective_id,
+        &path.to_string(),
+        "read_state",
+        Some(effective_id),
+    );
+    let res = fetch_state_path_(agent, path).await;
+    log.finish_value(entry, res.as_ref());
+    res
 }
 pub async fn fetch_metadata(
     agent: &Agent,
@@ -432,6 +659,22 @@ pub struct StatePath {
     kind: StateKind,
     result: StateType,
 }
+impl std::fmt::Display for StatePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for label in self.path.iter() {
+            match std::str::from_utf8(label.as_bytes()) {
+                Ok(s) if !s.is_empty() && s.chars().all(|c| c.is_ascii_graphic()) => {
+                    write!(f, "/{s}")?
+                }
+                _ if label.as_bytes().len() <= 29 => {
+                    write!(f, "/{}", Principal::from_slice(label.as_bytes()))?
+                }
+                _ => write!(f, "/{}", hex::encode(label.as_bytes()))?,
+            }
+        }
+        Ok(())
+    }
+}
 pub fn parse_state_path(paths: &[IDLValue]) -> anyhow::Result<StatePath> {
     let mut res = Vec::new();
     let mut prefix = String::new();
@@ -547,3 +790,59 @@ fn test_cast_type_big_num() {
         );
     }
 }
+
+#[test]
+fn test_reject_to_value() {
+    let reject = RejectResponse {
+        reject_code: RejectCode::SysTransient,
+        reject_message: "busy".to_string(),
+        error_code: None,
+    };
+    let err = anyhow::Error::from(AgentError::CertifiedReject(reject));
+    let v = reject_to_value(get_reject(&err).unwrap());
+    let expected = candid_parser::parse_idl_value(
+        r#"record { reject_code = variant { SysTransient }; reject_message = "busy"; error_code = null : opt text }"#,
+    )
+    .unwrap();
+    assert_eq!(v, expected);
+    assert!(get_reject(&anyhow!("not a reject")).is_none());
+    assert!(is_transient(&err));
+    let reject = RejectResponse {
+        reject_code: RejectCode::CanisterReject,
+        reject_message: "no".to_string(),
+        error_code: None,
+    };
+    assert!(!is_transient(&AgentError::CertifiedReject(reject).into()));
+    assert!(!is_transient(&anyhow!("not an agent error")));
+    let timeout = anyhow::Error::from(CallTimeout(std::time::Duration::from_millis(100)));
+    assert!(is_transient(&timeout));
+    assert_eq!(timeout.to_string(), "call timed out after 100 ms");
+}
+
+#[test]
+fn test_format() {
+    assert_eq!(format_size(1000), "1000 B");
+    assert_eq!(format_size(3 << 19), "1.50 MiB");
+    assert_eq!(
+        format_timestamp(1_700_000_000_123_000_000),
+        "2023-11-14T22:13:20.123Z"
+    );
+}
+
+#[test]
+fn test_collect_func_types() {
+    let prog = "type F = func (nat) -> (nat) query; type R = record { cb : F; next : opt R }; service : { f : () -> (vec R) }";
+    let ast: candid_parser::IDLProg = prog.parse().unwrap();
+    let mut env = TypeEnv::new();
+    candid_parser::check_prog(&mut env, &ast).unwrap();
+    let ty: Type = TypeInner::Vec(TypeInner::Var("R".to_string()).into()).into();
+    let v = candid_parser::parse_idl_value(
+        r#"vec { record { cb = func "aaaaa-aa".a; next = opt record { cb = func "aaaaa-aa".b; next = null } } }"#,
+    )
+    .unwrap();
+    let mut res = BTreeMap::new();
+    collect_func_types(&env, &ty, &v, &mut res);
+    let keys: Vec<_> = res.keys().map(|(_, m)| m.as_str()).collect();
+    assert_eq!(keys, ["a", "b"]);
+    assert!(res.values().all(|(_, f)| f.is_query()));
+}